@@ -0,0 +1,165 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Scaling of MMIO exit dispatch with the number of vCPU threads.
+//!
+//! Every thread hammers the same device through an `IoManager` serialized by
+//! a `Mutex`, an `IoManager` behind a `RwLock` and a `SharedIoManager`.
+
+#[macro_use]
+extern crate criterion;
+extern crate vm_device;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{BenchmarkId, Criterion};
+use vm_device::device_manager::{IoManager, SharedIoManager};
+use vm_device::resources::Resource;
+use vm_device::{DeviceIo, DeviceIoResult, IoAddress};
+
+const MMIO_BASE: u64 = 0xd000_0000;
+const MMIO_SIZE: u64 = 0x1000;
+const VCPUS: [usize; 4] = [1, 2, 4, 8];
+
+struct CounterDevice {
+    value: AtomicU32,
+}
+
+impl DeviceIo for CounterDevice {
+    fn read(&self, _base: IoAddress, _offset: IoAddress, data: &mut [u8]) -> DeviceIoResult<()> {
+        let value = self.value.load(Ordering::Relaxed).to_le_bytes();
+        let len = data.len().min(value.len());
+        data[..len].copy_from_slice(&value[..len]);
+        Ok(())
+    }
+
+    fn write(&self, _base: IoAddress, _offset: IoAddress, _data: &[u8]) -> DeviceIoResult<()> {
+        self.value.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn io_manager() -> IoManager {
+    let mut io_mgr = IoManager::new();
+    let device = Arc::new(CounterDevice {
+        value: AtomicU32::new(0),
+    });
+    let resources = [Resource::MmioAddressRange {
+        base: MMIO_BASE,
+        size: MMIO_SIZE,
+    }];
+    io_mgr.register_device_io(device, &resources).unwrap();
+    io_mgr
+}
+
+// Run `iters` exits on each of `vcpus` threads and return the wall time.
+fn run_vcpus<F>(vcpus: usize, iters: u64, exit: F) -> Duration
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    let exit = Arc::new(exit);
+    let barrier = Arc::new(Barrier::new(vcpus + 1));
+    let threads: Vec<_> = (0..vcpus)
+        .map(|_| {
+            let exit = exit.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..iters {
+                    exit(MMIO_BASE + (i % MMIO_SIZE) / 4 * 4);
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for t in threads {
+        t.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_mmio_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmio_read");
+    for vcpus in VCPUS.iter() {
+        group.bench_with_input(BenchmarkId::new("mutex", vcpus), vcpus, |b, &vcpus| {
+            let io_mgr = Arc::new(Mutex::new(io_manager()));
+            b.iter_custom(|iters| {
+                let io_mgr = io_mgr.clone();
+                run_vcpus(vcpus, iters, move |addr| {
+                    let mut data = [0u8; 4];
+                    io_mgr.lock().unwrap().mmio_read(addr, &mut data).unwrap();
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("rwlock", vcpus), vcpus, |b, &vcpus| {
+            let io_mgr = Arc::new(RwLock::new(io_manager()));
+            b.iter_custom(|iters| {
+                let io_mgr = io_mgr.clone();
+                run_vcpus(vcpus, iters, move |addr| {
+                    let mut data = [0u8; 4];
+                    io_mgr.read().unwrap().mmio_read(addr, &mut data).unwrap();
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("shared", vcpus), vcpus, |b, &vcpus| {
+            let io_mgr = Arc::new(SharedIoManager::new(io_manager()));
+            b.iter_custom(|iters| {
+                let io_mgr = io_mgr.clone();
+                run_vcpus(vcpus, iters, move |addr| {
+                    let mut data = [0u8; 4];
+                    io_mgr.mmio_read(addr, &mut data).unwrap();
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_mmio_read_while_updating(c: &mut Criterion) {
+    // One extra thread keeps registering and unregistering a second device
+    // while the vCPUs are dispatching exits.
+    let mut group = c.benchmark_group("mmio_read_while_updating");
+    for vcpus in VCPUS.iter() {
+        group.bench_with_input(BenchmarkId::new("shared", vcpus), vcpus, |b, &vcpus| {
+            let io_mgr = Arc::new(SharedIoManager::new(io_manager()));
+            b.iter_custom(|iters| {
+                let updater_mgr = io_mgr.clone();
+                let done = Arc::new(AtomicU32::new(0));
+                let updater_done = done.clone();
+                let updater = thread::spawn(move || {
+                    let device = Arc::new(CounterDevice {
+                        value: AtomicU32::new(0),
+                    });
+                    let resources = [Resource::MmioAddressRange {
+                        base: MMIO_BASE + MMIO_SIZE,
+                        size: MMIO_SIZE,
+                    }];
+                    while updater_done.load(Ordering::Relaxed) == 0 {
+                        let handle = updater_mgr
+                            .register_device_io(device.clone(), &resources)
+                            .unwrap();
+                        updater_mgr.unregister_device(handle).unwrap();
+                    }
+                });
+
+                let io_mgr = io_mgr.clone();
+                let elapsed = run_vcpus(vcpus, iters, move |addr| {
+                    let mut data = [0u8; 4];
+                    io_mgr.mmio_read(addr, &mut data).unwrap();
+                });
+                done.store(1, Ordering::Relaxed);
+                updater.join().unwrap();
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mmio_read, bench_mmio_read_while_updating);
+criterion_main!(benches);
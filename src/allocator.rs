@@ -0,0 +1,524 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Allocation of device resources from resource constraints.
+//!
+//! [ResourceAllocator](struct.ResourceAllocator.html) implements step 3) of
+//! the flow described in the [resources](../resources/index.html) module: it
+//! turns the constraints reported by a device into resources allocated from
+//! the VMM pools of PIO and MMIO addresses, legacy IRQs, MSI vectors and KVM
+//! memory slots.
+//!
+//! [SharedResourceAllocator](struct.SharedResourceAllocator.html) hands the
+//! allocated resources out in guards, so that the resources of a device
+//! failing to initialize are freed without the VMM tracking them.
+
+use std::result;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::device_manager::DeviceResourcesRef;
+use crate::interval::{self, AllocPolicy, IntervalAllocator};
+use crate::irq::{self, IrqAllocator};
+use crate::memslot::{self, MemSlotAllocator};
+use crate::resources::{DeviceResources, MsiIrqType, Resource, ResourceConstraint};
+
+/// Kind of resources managed by a `ResourceAllocator` pool.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoolKind {
+    /// IO port addresses.
+    Pio,
+    /// Memory mapped IO addresses.
+    Mmio,
+    /// Legacy IRQ numbers.
+    LegacyIrq,
+    /// MSI vectors.
+    Msi,
+    /// KVM memory slot indexes.
+    KvmMemSlot,
+}
+
+/// Error type for resource allocation.
+#[derive(Debug)]
+pub enum Error {
+    /// The constraint at this index has a zero size, an alignment which isn't
+    /// a power of two, an empty allocation window or a number of MSI vectors
+    /// unsupported by their type.
+    InvalidConstraint(usize),
+    /// The pool has no room left for the constraint at this index.
+    Exhausted {
+        /// Index of the constraint.
+        index: usize,
+        /// The exhausted pool.
+        pool: PoolKind,
+    },
+    /// The resources requested by the constraint at this index are already
+    /// allocated or not part of the pool.
+    Unavailable {
+        /// Index of the constraint.
+        index: usize,
+        /// The pool the resources were requested from.
+        pool: PoolKind,
+    },
+    /// The resource being freed isn't allocated from the pools.
+    NotAllocated(Resource),
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Allocator of device resources, satisfying resource constraints from the
+/// pools of resources available to a VM.
+#[derive(Clone)]
+pub struct ResourceAllocator {
+    pio: IntervalAllocator<u16>,
+    mmio: IntervalAllocator<u64>,
+    irq: IrqAllocator,
+    mem_slots: MemSlotAllocator,
+}
+
+impl ResourceAllocator {
+    /// Create an allocator with pools made of the inclusive ranges of IO
+    /// ports `pio`, of MMIO addresses `mmio`, of legacy IRQs `legacy_irq` and
+    /// of MSI vectors `msi`, and of the `mem_slots` KVM memory slots of the
+    /// VM.
+    pub fn new(
+        pio: (u16, u16),
+        mmio: (u64, u64),
+        legacy_irq: (u32, u32),
+        msi: (u32, u32),
+        mem_slots: u32,
+    ) -> Self {
+        ResourceAllocator {
+            pio: IntervalAllocator::new(pio.0, pio.1),
+            mmio: IntervalAllocator::new(mmio.0, mmio.1),
+            irq: IrqAllocator::new(legacy_irq, msi),
+            mem_slots: MemSlotAllocator::new(mem_slots),
+        }
+    }
+
+    /// Allocate resources satisfying all the `constraints`, in order.
+    ///
+    /// Either all the constraints are satisfied, or nothing is allocated and
+    /// the error reports the first constraint which couldn't be.
+    pub fn allocate(&mut self, constraints: &[ResourceConstraint]) -> Result<DeviceResources> {
+        let mut resources = DeviceResources::new();
+        for (index, constraint) in constraints.iter().enumerate() {
+            match self.allocate_one(index, constraint) {
+                Ok(allocated) => {
+                    for res in allocated {
+                        resources.append(res);
+                    }
+                }
+                Err(e) => {
+                    // Everything freed has just been allocated.
+                    let _ = self.free(&resources);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(resources)
+    }
+
+    /// Set where the PIO ranges are placed in their pool.
+    pub fn set_pio_policy(&mut self, policy: AllocPolicy) {
+        self.pio.set_policy(policy);
+    }
+
+    /// Set where the MMIO ranges are placed in their pool.
+    pub fn set_mmio_policy(&mut self, policy: AllocPolicy) {
+        self.mmio.set_policy(policy);
+    }
+
+    /// Return the allocator of KVM memory slots, e.g. to reserve the slots
+    /// used by the boot RAM.
+    pub fn mem_slots_mut(&mut self) -> &mut MemSlotAllocator {
+        &mut self.mem_slots
+    }
+
+    fn allocate_one(
+        &mut self,
+        index: usize,
+        constraint: &ResourceConstraint,
+    ) -> Result<Vec<Resource>> {
+        let err = |pool| move |e| constraint_error(index, pool, e);
+        match *constraint {
+            ResourceConstraint::PioAddress { range, align, size } => {
+                let base = self
+                    .pio
+                    .allocate(size, align, range)
+                    .map_err(err(PoolKind::Pio))?;
+                Ok(vec![Resource::PioAddressRange { base, size }])
+            }
+            ResourceConstraint::MmioAddress { range, align, size } => {
+                let base = self
+                    .mmio
+                    .allocate(size, align, range)
+                    .map_err(err(PoolKind::Mmio))?;
+                Ok(vec![Resource::MmioAddressRange { base, size }])
+            }
+            ResourceConstraint::LegacyIrq { irq: Some(irq) } => {
+                self.irq
+                    .reserve_legacy(irq)
+                    .map_err(|e| irq_error(index, e))?;
+                Ok(vec![Resource::LegacyIrq(irq)])
+            }
+            ResourceConstraint::LegacyIrq { irq: None } => {
+                let irq = self
+                    .irq
+                    .allocate_legacy()
+                    .map_err(|e| irq_error(index, e))?;
+                Ok(vec![Resource::LegacyIrq(irq)])
+            }
+            ResourceConstraint::PciMsiIrq { size } => {
+                self.allocate_msi(index, MsiIrqType::PciMsi, size)
+            }
+            ResourceConstraint::PciMsixIrq { size } => {
+                self.allocate_msi(index, MsiIrqType::PciMsix, size)
+            }
+            ResourceConstraint::GenericIrq { size } => {
+                self.allocate_msi(index, MsiIrqType::GenericMsi, size)
+            }
+            ResourceConstraint::KvmMemSlot { slot, size } => {
+                let slots = match slot {
+                    Some(slot) => self
+                        .mem_slots
+                        .reserve(slot, size)
+                        .map(|_| (slot..slot + size).collect()),
+                    None => self.mem_slots.allocate_scattered(size, 0),
+                }
+                .map_err(|e| mem_slot_error(index, e))?;
+                Ok(slots.into_iter().map(Resource::KvmMemSlot).collect())
+            }
+        }
+    }
+
+    fn allocate_msi(&mut self, index: usize, ty: MsiIrqType, size: u32) -> Result<Vec<Resource>> {
+        let base = self
+            .irq
+            .allocate_msi(ty, size)
+            .map_err(|e| irq_error(index, e))?;
+        Ok(vec![Resource::MsiIrq { ty, base, size }])
+    }
+
+    /// Give the `resources` back to the pools, e.g. once the device owning
+    /// them is removed.
+    ///
+    /// Resources which don't come from the pools, like MAC addresses, are
+    /// ignored. All the allocated resources are freed even if some of them
+    /// aren't, the first of which is reported.
+    pub fn free(&mut self, resources: &DeviceResources) -> Result<()> {
+        let mut result = Ok(());
+        for res in resources.get_all_resources() {
+            let freed = match *res {
+                Resource::PioAddressRange { base, size } => self.pio.free(base, size).is_ok(),
+                Resource::MmioAddressRange { base, size } => self.mmio.free(base, size).is_ok(),
+                Resource::LegacyIrq(_) | Resource::MsiIrq { .. } => self.irq.free(res).is_ok(),
+                Resource::KvmMemSlot(slot) => self.mem_slots.free(slot).is_ok(),
+                Resource::MacAddresss(_) => true,
+            };
+            if !freed && result.is_ok() {
+                result = Err(Error::NotAllocated(res.clone()));
+            }
+        }
+        result
+    }
+}
+
+/// `ResourceAllocator` shared by the threads adding and removing devices.
+#[derive(Clone)]
+pub struct SharedResourceAllocator {
+    inner: Arc<Mutex<ResourceAllocator>>,
+}
+
+impl SharedResourceAllocator {
+    /// Create a `SharedResourceAllocator` allocating from `allocator`.
+    pub fn new(allocator: ResourceAllocator) -> Self {
+        SharedResourceAllocator {
+            inner: Arc::new(Mutex::new(allocator)),
+        }
+    }
+
+    /// Lock the allocator, e.g. to reserve resources at fixed locations.
+    pub fn lock(&self) -> MutexGuard<'_, ResourceAllocator> {
+        // Allocations are all-or-nothing, the pools stay consistent even if
+        // a thread panicked while holding the lock.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Allocate resources satisfying all the `constraints`, in order.
+    ///
+    /// The resources are given back to the pools when the returned guard is
+    /// dropped, unless it is committed, e.g. by registering the device with
+    /// `IoManager::register_device_io`.
+    ///
+    /// See [ResourceAllocator::allocate](struct.ResourceAllocator.html#method.allocate).
+    pub fn allocate(&self, constraints: &[ResourceConstraint]) -> Result<ResourceGuard> {
+        let resources = self.lock().allocate(constraints)?;
+        Ok(ResourceGuard {
+            allocator: self.clone(),
+            resources: Some(resources),
+        })
+    }
+
+    /// Give the `resources` back to the pools.
+    ///
+    /// See [ResourceAllocator::free](struct.ResourceAllocator.html#method.free).
+    pub fn free(&self, resources: &DeviceResources) -> Result<()> {
+        self.lock().free(resources)
+    }
+}
+
+/// Resources allocated by a `SharedResourceAllocator`, given back to the pools
+/// on drop unless committed.
+///
+/// This way, resources of a device failing to initialize can't leak.
+pub struct ResourceGuard {
+    allocator: SharedResourceAllocator,
+    // Only `None` once committed or dropped.
+    resources: Option<DeviceResources>,
+}
+
+impl ResourceGuard {
+    /// Return the guarded resources.
+    pub fn resources(&self) -> &DeviceResources {
+        // Only taken by `commit()` and `drop()`, which consume the guard.
+        self.resources.as_ref().unwrap()
+    }
+
+    /// Keep the resources allocated, and return them.
+    ///
+    /// They must then be freed with `SharedResourceAllocator::free` once the
+    /// device is removed, e.g. from the resources given back by
+    /// `IoManager::unregister_device`.
+    pub fn commit(mut self) -> DeviceResources {
+        self.resources.take().unwrap()
+    }
+}
+
+impl DeviceResourcesRef for ResourceGuard {
+    fn resources(&self) -> &[Resource] {
+        ResourceGuard::resources(self).get_all_resources()
+    }
+
+    fn commit(self) {
+        ResourceGuard::commit(self);
+    }
+}
+
+impl Drop for ResourceGuard {
+    fn drop(&mut self) {
+        if let Some(resources) = self.resources.take() {
+            // Nothing can be done about resources which weren't allocated.
+            let _ = self.allocator.free(&resources);
+        }
+    }
+}
+
+// Report the failure of the allocator of `pool` to satisfy the constraint at
+// `index`.
+fn constraint_error(index: usize, pool: PoolKind, e: interval::Error) -> Error {
+    match e {
+        interval::Error::Exhausted => Error::Exhausted { index, pool },
+        interval::Error::Unavailable { .. } => Error::Unavailable { index, pool },
+        _ => Error::InvalidConstraint(index),
+    }
+}
+
+// Report the failure of the IRQ allocator to satisfy the constraint at
+// `index`.
+fn irq_error(index: usize, e: irq::Error) -> Error {
+    match e {
+        irq::Error::LegacyUnavailable(_) => Error::Unavailable {
+            index,
+            pool: PoolKind::LegacyIrq,
+        },
+        irq::Error::LegacyExhausted => Error::Exhausted {
+            index,
+            pool: PoolKind::LegacyIrq,
+        },
+        irq::Error::MsiExhausted { .. } => Error::Exhausted {
+            index,
+            pool: PoolKind::Msi,
+        },
+        _ => Error::InvalidConstraint(index),
+    }
+}
+
+// Report the failure of the KVM memory slot allocator to satisfy the
+// constraint at `index`.
+fn mem_slot_error(index: usize, e: memslot::Error) -> Error {
+    let pool = PoolKind::KvmMemSlot;
+    match e {
+        memslot::Error::Unavailable(_) => Error::Unavailable { index, pool },
+        memslot::Error::Exhausted { .. } => Error::Exhausted { index, pool },
+        _ => Error::InvalidConstraint(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device_manager::{self, IoManager, OpenBus};
+
+    fn allocator() -> ResourceAllocator {
+        ResourceAllocator::new(
+            (0x1000, 0x1fff),
+            (0xd000_0000, 0xdfff_ffff),
+            (5, 15),
+            (24, 87),
+            32,
+        )
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut allocator = allocator();
+        allocator.mem_slots_mut().reserve(0, 1).unwrap();
+        let constraints = [
+            ResourceConstraint::new_pio(8),
+            ResourceConstraint::pio_with_constraints(4, Some((0x1800, 0x1fff)), 0x10),
+            ResourceConstraint::new_mmio(0x1000),
+            ResourceConstraint::mmio_with_constraints(0x2000, None, 0x4000),
+            ResourceConstraint::new_legacy_irq(Some(7)),
+            ResourceConstraint::new_legacy_irq(None),
+            ResourceConstraint::PciMsiIrq { size: 4 },
+            ResourceConstraint::PciMsixIrq { size: 8 },
+            ResourceConstraint::new_kvm_mem_slot(2, None),
+            ResourceConstraint::new_kvm_mem_slot(1, Some(10)),
+        ];
+        let resources = allocator.allocate(&constraints).unwrap();
+        assert_eq!(
+            resources.get_pio_address_ranges(),
+            vec![(0x1000, 8), (0x1800, 4)]
+        );
+        assert_eq!(
+            resources.get_mmio_address_ranges(),
+            vec![(0xd000_0000, 0x1000), (0xd000_4000, 0x2000)]
+        );
+        assert_eq!(resources.get_legacy_irq(), Some(7));
+        assert_eq!(resources.get_all_resources()[5], Resource::LegacyIrq(5));
+        assert_eq!(resources.get_pci_msi_irqs(), Some((24, 4)));
+        assert_eq!(resources.get_pci_msix_irqs(), Some((28, 8)));
+        assert_eq!(resources.get_kvm_mem_slots(), vec![1, 2, 10]);
+
+        // Freed resources can be allocated again.
+        assert!(allocator.allocate(&constraints).is_err());
+        assert!(allocator.free(&resources).is_ok());
+        match allocator.free(&resources) {
+            Err(Error::NotAllocated(res)) => assert_eq!(
+                res,
+                Resource::PioAddressRange {
+                    base: 0x1000,
+                    size: 8
+                }
+            ),
+            _ => panic!("resources were freed twice"),
+        }
+        assert!(allocator.allocate(&constraints).is_ok());
+    }
+
+    #[test]
+    fn test_allocate_failure() {
+        let mut allocator = allocator();
+        let constraints = [
+            ResourceConstraint::new_pio(8),
+            ResourceConstraint::new_legacy_irq(Some(3)),
+        ];
+        match allocator.allocate(&constraints) {
+            Err(Error::Unavailable { index, pool }) => {
+                assert_eq!(index, 1);
+                assert_eq!(pool, PoolKind::LegacyIrq);
+            }
+            _ => panic!("IRQ out of the pool was allocated"),
+        }
+
+        // Nothing was allocated by the failed attempt.
+        let constraints = [ResourceConstraint::new_pio(0x1000)];
+        assert!(allocator.allocate(&constraints).is_ok());
+        match allocator.allocate(&constraints) {
+            Err(Error::Exhausted { index, pool }) => {
+                assert_eq!(index, 0);
+                assert_eq!(pool, PoolKind::Pio);
+            }
+            _ => panic!("PIO pool wasn't exhausted"),
+        }
+
+        let constraints = [
+            ResourceConstraint::new_legacy_irq(None),
+            ResourceConstraint::mmio_with_constraints(0x1000, None, 0x3000),
+        ];
+        match allocator.allocate(&constraints) {
+            Err(Error::InvalidConstraint(index)) => assert_eq!(index, 1),
+            _ => panic!("invalid alignment was accepted"),
+        }
+        let constraints = [ResourceConstraint::PciMsiIrq { size: 3 }];
+        match allocator.allocate(&constraints) {
+            Err(Error::InvalidConstraint(index)) => assert_eq!(index, 0),
+            _ => panic!("invalid MSI count was accepted"),
+        }
+        let constraints = [ResourceConstraint::new_kvm_mem_slot(u32::MAX, None)];
+        match allocator.allocate(&constraints) {
+            Err(Error::Exhausted { index, pool }) => {
+                assert_eq!(index, 0);
+                assert_eq!(pool, PoolKind::KvmMemSlot);
+            }
+            _ => panic!("more memory slots than the VM has were allocated"),
+        }
+        let constraints = [ResourceConstraint::new_kvm_mem_slot(32, None)];
+        assert!(allocator.allocate(&constraints).is_ok());
+    }
+
+    #[test]
+    fn test_resource_guard() {
+        let allocator = SharedResourceAllocator::new(allocator());
+        let constraints = [
+            ResourceConstraint::new_pio(0x800),
+            ResourceConstraint::new_legacy_irq(None),
+        ];
+        let guard = allocator.allocate(&constraints).unwrap();
+        assert_eq!(guard.resources().get_legacy_irq(), Some(5));
+        let other = allocator.allocate(&constraints).unwrap();
+        assert!(allocator.allocate(&constraints).is_err());
+
+        // Dropped guards give their resources back.
+        drop(guard);
+        let resources = allocator.allocate(&constraints).unwrap().commit();
+        assert!(allocator.allocate(&constraints).is_err());
+        assert!(allocator.free(&resources).is_ok());
+        drop(other);
+
+        // Registered devices keep their resources until they are unplugged.
+        let mut io_mgr = IoManager::new();
+        let guard = allocator.allocate(&constraints).unwrap();
+        let handle = io_mgr
+            .register_device_io(Arc::new(OpenBus::new()), guard)
+            .unwrap();
+        let guard = allocator.allocate(&constraints).unwrap();
+        assert!(allocator.allocate(&constraints).is_err());
+        let (_, resources) = io_mgr.unregister_device(handle).unwrap();
+        assert!(allocator.free(&resources.into()).is_ok());
+        let unplugged = allocator.allocate(&constraints).unwrap();
+        io_mgr
+            .register_device_io(Arc::new(OpenBus::new()), unplugged)
+            .unwrap();
+        drop(guard);
+
+        // Devices failing to register don't.
+        let conflict = Resource::PioAddressRange {
+            base: 0x1800,
+            size: 0x800,
+        };
+        io_mgr
+            .register_device_io(Arc::new(OpenBus::new()), &[conflict])
+            .unwrap();
+        let guard = allocator.allocate(&constraints).unwrap();
+        match io_mgr.register_device_io(Arc::new(OpenBus::new()), guard) {
+            Err(device_manager::Error::DeviceOverlap { .. }) => {}
+            _ => panic!("overlapping device was registered"),
+        }
+        assert!(allocator.allocate(&constraints).is_ok());
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Coalesced MMIO: guest writes batched in a ring instead of exiting.
+//!
+//! Writes to coalesced MMIO zones, e.g. declared with
+//! KVM_REGISTER_COALESCED_MMIO, are appended to a
+//! [CoalescedRing](trait.CoalescedRing.html) by the hypervisor. The VMM
+//! replays them later on, in order, with
+//! [IoManager::drain_coalesced](../device_manager/struct.IoManager.html#method.drain_coalesced).
+
+use std::collections::VecDeque;
+
+/// MMIO write batched in a coalesced MMIO ring.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CoalescedEntry {
+    addr: u64,
+    len: usize,
+    data: [u8; 8],
+}
+
+impl CoalescedEntry {
+    /// Create an entry for writing `data` at the guest address `addr`.
+    ///
+    /// Return `None` if `data` is larger than 8 bytes.
+    pub fn new(addr: u64, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+        let mut entry = CoalescedEntry {
+            addr,
+            len: data.len(),
+            data: [0; 8],
+        };
+        entry.data[..data.len()].copy_from_slice(data);
+        Some(entry)
+    }
+
+    /// Guest address of the write.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Data written by the guest.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Ring of pending coalesced MMIO writes, e.g. the KVM coalesced MMIO page.
+pub trait CoalescedRing {
+    /// Remove and return the oldest pending write, if any.
+    fn pop(&mut self) -> Option<CoalescedEntry>;
+}
+
+/// Coalesced MMIO ring implemented in software, e.g. for tests.
+pub struct SoftwareRing {
+    entries: VecDeque<CoalescedEntry>,
+    capacity: usize,
+}
+
+impl SoftwareRing {
+    /// Create a ring holding up to `capacity` pending writes.
+    pub fn new(capacity: usize) -> Self {
+        SoftwareRing {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Append a write to the ring.
+    ///
+    /// Return `false` if the ring is full, in which case the hypervisor would
+    /// exit to let the VMM drain the ring.
+    pub fn push(&mut self, entry: CoalescedEntry) -> bool {
+        if self.entries.len() == self.capacity {
+            return false;
+        }
+        self.entries.push_back(entry);
+        true
+    }
+
+    /// Number of pending writes.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether there is no pending write.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl CoalescedRing for SoftwareRing {
+    fn pop(&mut self) -> Option<CoalescedEntry> {
+        self.entries.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesced_entry() {
+        let entry = CoalescedEntry::new(0x1000, &[1, 2, 3]).unwrap();
+        assert_eq!(entry.addr(), 0x1000);
+        assert_eq!(entry.data(), &[1, 2, 3]);
+        assert!(CoalescedEntry::new(0x1000, &[0; 9]).is_none());
+    }
+
+    #[test]
+    fn test_software_ring() {
+        let mut ring = SoftwareRing::new(2);
+        assert!(ring.is_empty());
+        let first = CoalescedEntry::new(0x1000, &[1]).unwrap();
+        let second = CoalescedEntry::new(0x1001, &[2]).unwrap();
+        assert!(ring.push(first));
+        assert!(ring.push(second));
+        assert!(!ring.push(first));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop(), Some(first));
+        assert_eq!(ring.pop(), Some(second));
+        assert_eq!(ring.pop(), None);
+    }
+}
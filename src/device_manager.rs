@@ -10,22 +10,122 @@
 //！VMM would be responsible for getting device resource request, ask
 //! vm_allocator to allocate the resources, ask vm_device to register the
 //! devices IO ranges, and finally set resources to virtual device.
+//!
+//! [SharedIoManager](struct.SharedIoManager.html) wraps an `IoManager` for
+//! VMMs dispatching VM exits from many vCPU threads: exits are handled on an
+//! immutable snapshot without taking any lock, while updates build a new
+//! snapshot and publish it atomically.
 
-use crate::resources::Resource;
-use crate::{DeviceIo, IoAddress, IoSize};
+use crate::coalesced::CoalescedRing;
+use crate::ioevent::{IoEvent, IoEventBackend, IoEventNotifier};
+use crate::region::IoRegion;
+use crate::resources::{DeviceResources, Resource};
+use crate::stats::{IoCounters, IoStats};
+use crate::trace::{IoAccess, IoTracer};
+use crate::{DeviceId, DeviceIo, DeviceIoError, DeviceIoResult, IoAddress, IoDirection, IoSize};
 
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
 use std::collections::btree_map::BTreeMap;
+use std::fmt;
+use std::io;
+use std::ops::Bound::{Excluded, Unbounded};
 use std::result;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use arc_swap::{ArcSwap, Guard};
 
 /// Error type for `IoManager` usage.
 #[derive(Debug)]
 pub enum Error {
     /// The inserting device overlaps with a current device.
-    DeviceOverlap,
+    DeviceOverlap {
+        /// The range being registered.
+        range: Resource,
+        /// The registered range it conflicts with.
+        existing: Resource,
+        /// Identity of the device owning `existing`, if it has one.
+        device: Option<DeviceId>,
+    },
     /// The device doesn't exist.
     NoDevice,
+    /// The device doesn't own the IO range.
+    RangeNotFound(Resource),
+    /// The IO range doesn't fit in the bus address space or in the window
+    /// it aliases.
+    InvalidRange(Resource),
+    /// The access violates the access policy of the IO range.
+    InvalidAccess {
+        /// The guest address of the access.
+        addr: IoAddress,
+        /// The length of the access.
+        len: usize,
+        /// Identity of the device owning the IO range, if it has one.
+        device: Option<DeviceId>,
+    },
+    /// The access runs past the end of an IO range.
+    RangeCrossing {
+        /// The guest address of the access.
+        addr: IoAddress,
+        /// The length of the access.
+        len: usize,
+        /// Identity of the device owning the IO range, if it has one.
+        device: Option<DeviceId>,
+    },
+    /// The IO event length or data match is invalid.
+    InvalidIoEvent(IoEvent),
+    /// The IO event collides with a registered one.
+    IoEventExists(IoEvent),
+    /// The IO event isn't registered.
+    IoEventNotFound(IoEvent),
+    /// The IO event backend failed to offload the registration.
+    IoEventBackend(io::Error),
+    /// Signaling the notifier of a matching IO event failed.
+    IoEventNotify {
+        /// The guest address of the write.
+        addr: IoAddress,
+        /// Identity of the device owning the written address, if it has one.
+        device: Option<DeviceId>,
+        /// The error reported by the notifier.
+        error: io::Error,
+    },
+    /// The coalesced MMIO zone is empty or runs past the end of the bus.
+    InvalidCoalescedZone {
+        /// Base address of the zone.
+        base: u64,
+        /// Size of the zone.
+        size: u64,
+    },
+    /// The coalesced MMIO zone overlaps with a current zone.
+    CoalescedZoneOverlap {
+        /// Base address of the current zone.
+        base: u64,
+        /// Size of the current zone.
+        size: u64,
+    },
+    /// The coalesced MMIO zone isn't registered.
+    CoalescedZoneNotFound {
+        /// Base address of the zone.
+        base: u64,
+        /// Size of the zone.
+        size: u64,
+    },
+    /// A coalesced write targets an address outside of any coalesced zone.
+    NotCoalesced(IoAddress),
+    /// The device failed to handle the access.
+    DeviceIo {
+        /// The guest address of the failed access.
+        addr: IoAddress,
+        /// Identity of the device, if it has one.
+        device: Option<DeviceId>,
+        /// The error reported by the device.
+        error: DeviceIoError,
+    },
+    /// Operations of a transaction failed, nothing was committed.
+    Transaction {
+        /// Index of each failed operation in the transaction, with its error.
+        failures: Vec<(usize, Error)>,
+    },
 }
 
 /// Simplify the `Result` type.
@@ -51,6 +151,73 @@ impl IoRange {
             size: IoSize::Mmio(size),
         }
     }
+
+    // Check whether two ranges on the same bus share any address. Ranges with
+    // the same base always conflict since they would share the bus key.
+    fn overlaps(&self, other: &IoRange) -> bool {
+        let (base, other_base) = (self.base.raw_value(), other.base.raw_value());
+        if base >= other_base {
+            base == other_base || base - other_base < other.size.raw_value()
+        } else {
+            other_base - base < self.size.raw_value()
+        }
+    }
+
+    // Number of addresses of the bus of `addr`.
+    fn bus_size(addr: IoAddress) -> u128 {
+        match addr {
+            IoAddress::Pio(_) => 1 << 16,
+            IoAddress::Mmio(_) => 1 << 64,
+        }
+    }
+
+    // Check whether the range lies within the address space of its bus.
+    fn fits_bus(&self) -> bool {
+        u128::from(self.base.raw_value()) + u128::from(self.size.raw_value())
+            <= Self::bus_size(self.base)
+    }
+
+    // Check whether the range is non empty and lies within its bus.
+    fn is_valid(&self) -> bool {
+        self.size.raw_value() != 0 && self.fits_bus()
+    }
+
+    // Create a range of `size` bytes on the same bus as `addr`, starting
+    // `start` bytes after it, if it lies within the bus address space.
+    fn new_within_bus(addr: IoAddress, start: u128, size: u64) -> Option<Self> {
+        let base = u128::from(addr.raw_value()) + start;
+        let bus_size = Self::bus_size(addr);
+        if u128::from(size) >= bus_size || base + u128::from(size) > bus_size {
+            return None;
+        }
+        Some(Self::new_range(addr, base as u64, size))
+    }
+
+    // Create a range on the same bus as `addr`.
+    fn new_range(addr: IoAddress, base: u64, size: u64) -> Self {
+        match addr {
+            IoAddress::Pio(_) => IoRange::new_pio_range(base as u16, size as u16),
+            IoAddress::Mmio(_) => IoRange::new_mmio_range(base, size),
+        }
+    }
+
+    fn from_resource(res: &Resource) -> Option<Self> {
+        match *res {
+            Resource::PioAddressRange { base, size } => Some(IoRange::new_pio_range(base, size)),
+            Resource::MmioAddressRange { base, size } => Some(IoRange::new_mmio_range(base, size)),
+            _ => None,
+        }
+    }
+
+    fn to_resource(self) -> Resource {
+        match (self.base, self.size) {
+            (IoAddress::Pio(base), IoSize::Pio(size)) => Resource::PioAddressRange { base, size },
+            (base, size) => Resource::MmioAddressRange {
+                base: base.raw_value(),
+                size: size.raw_value(),
+            },
+        }
+    }
 }
 
 impl Eq for IoRange {}
@@ -69,17 +236,278 @@ impl Ord for IoRange {
 
 impl PartialOrd for IoRange {
     fn partial_cmp(&self, other: &IoRange) -> Option<Ordering> {
-        self.base.partial_cmp(&other.base)
+        Some(self.cmp(other))
+    }
+}
+
+/// Action taken by `IoManager` on an access violating an `AccessPolicy`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ViolationAction {
+    /// Split the access into smaller accesses allowed by the policy.
+    Split,
+    /// Fail the access with `Error::InvalidAccess`.
+    Reject,
+    /// Forward the access to the device as is.
+    PassThrough,
+}
+
+/// Access size and alignment policy of a registered IO range.
+///
+/// `IoManager` enforces the policy before calling into the device, so the
+/// device only ever sees accesses it supports. The default policy forwards
+/// any access to the device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AccessPolicy {
+    // Bit `n` is set when accesses of `1 << n` bytes are allowed.
+    widths: u8,
+    aligned: bool,
+    on_violation: ViolationAction,
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        AccessPolicy::new(&[1, 2, 4, 8], false, ViolationAction::PassThrough)
+    }
+}
+
+impl AccessPolicy {
+    /// Create a new access policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `widths`: allowed access widths in bytes, among 1, 2, 4 and 8. Other
+    ///   widths are ignored.
+    /// * `aligned`: whether accesses must be naturally aligned.
+    /// * `on_violation`: what to do with accesses violating the policy.
+    pub fn new(widths: &[usize], aligned: bool, on_violation: ViolationAction) -> Self {
+        let widths = widths
+            .iter()
+            .filter(|w| [1, 2, 4, 8].contains(*w))
+            .fold(0, |mask, w| mask | *w as u8);
+        AccessPolicy {
+            widths,
+            aligned,
+            on_violation,
+        }
+    }
+
+    /// Check whether an access of `len` bytes at `addr` complies with the policy.
+    pub fn allows(&self, addr: u64, len: usize) -> bool {
+        len <= 8
+            && self.widths & len as u8 != 0
+            && len.is_power_of_two()
+            && (!self.aligned || addr.is_multiple_of(len as u64))
+    }
+
+    // Split an access of `len` bytes at `addr` into the widths of allowed
+    // accesses covering it, picking the widest access at each step.
+    fn split(&self, addr: u64, len: usize) -> Option<Vec<usize>> {
+        let mut widths = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let pos = addr.wrapping_add(done as u64);
+            let width = [8, 4, 2, 1]
+                .iter()
+                .cloned()
+                .find(|w| *w <= len - done && self.allows(pos, *w))?;
+            widths.push(width);
+            done += width;
+        }
+        Some(widths)
+    }
+}
+
+/// How `IoManager` handles accesses running past the end of an IO range.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum CrossingPolicy {
+    /// Split the access across the adjacent ranges, in address order.
+    Split,
+    /// Fail the access with `Error::RangeCrossing`.
+    #[default]
+    Reject,
+}
+
+/// Resources of a device being registered to an `IoManager`.
+///
+/// Implemented by slices of resources, and by guards releasing their
+/// resources unless the registration succeeds.
+pub trait DeviceResourcesRef {
+    /// Return the resources to register.
+    fn resources(&self) -> &[Resource];
+
+    /// Called once the device is registered with the resources.
+    fn commit(self)
+    where
+        Self: Sized,
+    {
+    }
+}
+
+impl DeviceResourcesRef for &[Resource] {
+    fn resources(&self) -> &[Resource] {
+        self
+    }
+}
+
+impl<const N: usize> DeviceResourcesRef for &[Resource; N] {
+    fn resources(&self) -> &[Resource] {
+        &self[..]
+    }
+}
+
+impl DeviceResourcesRef for &Vec<Resource> {
+    fn resources(&self) -> &[Resource] {
+        self
+    }
+}
+
+impl DeviceResourcesRef for &DeviceResources {
+    fn resources(&self) -> &[Resource] {
+        self.get_all_resources()
+    }
+}
+
+/// Opaque handle on a device registered to an `IoManager`.
+///
+/// Handles are never reused by an `IoManager`, so a stale handle can't be
+/// mistaken for a device registered later on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceHandle(u64);
+
+impl fmt::Display for DeviceHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "device {}", self.0)
+    }
+}
+
+// Bus entry for a registered IO range.
+#[derive(Clone)]
+struct IoEntry {
+    handle: DeviceHandle,
+    device: Arc<dyn DeviceIo>,
+    // Base address passed to the device.
+    base: IoAddress,
+    // Offset passed to the device for accesses to the start of the range.
+    offset: u64,
+    policy: AccessPolicy,
+    // Disabled ranges stay registered but don't decode any access.
+    enabled: bool,
+    stats: Option<Arc<IoCounters>>,
+}
+
+// Device registered to an `IoManager`, with the resources it owns.
+#[derive(Clone)]
+struct DeviceEntry {
+    device: Arc<dyn DeviceIo>,
+    resources: Vec<Resource>,
+    // Device the entry is an alias of, if any.
+    alias_of: Option<DeviceHandle>,
+}
+
+// IO region tree registered to an `IoManager`.
+#[derive(Clone)]
+struct RegionEntry {
+    root: Arc<IoRegion>,
+    base: IoAddress,
+    // Handle of each device region, indexed as in `FlatView::leaves`, or
+    // `None` once the device is unregistered.
+    handles: Vec<Option<DeviceHandle>>,
+}
+
+// IO event registered to an `IoManager`, with the backend it was offloaded to.
+#[derive(Clone)]
+struct IoEventEntry {
+    event: IoEvent,
+    notifier: Arc<dyn IoEventNotifier>,
+    backend: Option<Arc<dyn IoEventBackend>>,
+}
+
+/// IO range registered to an `IoManager`, as listed by its introspection API.
+pub struct MappedRange<'a> {
+    /// The PIO or MMIO range.
+    pub range: Resource,
+    /// Handle of the device owning the range.
+    pub handle: DeviceHandle,
+    /// The device handling accesses to the range.
+    pub device: &'a Arc<dyn DeviceIo>,
+    /// Device the range is an alias of, if any.
+    pub alias_of: Option<DeviceHandle>,
+    /// Whether accesses to the range are decoded.
+    pub enabled: bool,
+    /// Accesses to the range so far, if statistics are enabled.
+    pub stats: Option<IoStats>,
+}
+
+impl<'a> fmt::Display for MappedRange<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.range {
+            Resource::PioAddressRange { base, size } => write!(
+                f,
+                "{:#06x}-{:#06x}: {}",
+                base,
+                base.wrapping_add(size.wrapping_sub(1)),
+                self.handle
+            )?,
+            Resource::MmioAddressRange { base, size } => write!(
+                f,
+                "{:#018x}-{:#018x}: {}",
+                base,
+                base.wrapping_add(size.wrapping_sub(1)),
+                self.handle
+            )?,
+            _ => write!(f, "{:?}: {}", self.range, self.handle)?,
+        }
+        if let Some(id) = self.device.id() {
+            write!(f, ", {}", id)?;
+        }
+        if let Some(owner) = self.alias_of {
+            write!(f, " (alias of {})", owner)?;
+        }
+        if !self.enabled {
+            write!(f, " [disabled]")?;
+        }
+        Ok(())
     }
 }
 
 /// System IO manager serving for all devices management and VM exit handling.
-#[derive(Default)]
+///
+/// `IoManager` is `Send + Sync`: once populated, it can be put in an `Arc`
+/// and VM exits dispatched concurrently from all vCPU threads.
+#[derive(Clone, Default)]
 pub struct IoManager {
+    /// Registered devices, indexed by their handle.
+    devices: BTreeMap<DeviceHandle, DeviceEntry>,
+    /// Handle of the next registered device.
+    next_handle: u64,
     /// Range mapping for VM exit pio operations.
-    pio_bus: BTreeMap<IoRange, Arc<dyn DeviceIo>>,
+    pio_bus: BTreeMap<IoRange, IoEntry>,
     /// Range mapping for VM exit mmio operations.
-    mmio_bus: BTreeMap<IoRange, Arc<dyn DeviceIo>>,
+    mmio_bus: BTreeMap<IoRange, IoEntry>,
+    /// Registered IO region trees.
+    regions: Vec<RegionEntry>,
+    /// Device handling pio operations no registered device claims.
+    pio_fallback: Option<Arc<dyn DeviceIo>>,
+    /// Device handling mmio operations no registered device claims.
+    mmio_fallback: Option<Arc<dyn DeviceIo>>,
+    /// Handling of accesses crossing the end of a range.
+    crossing: CrossingPolicy,
+    /// IO events for pio writes, indexed by port.
+    pio_ioevents: BTreeMap<u64, Vec<IoEventEntry>>,
+    /// IO events for mmio writes, indexed by address.
+    mmio_ioevents: BTreeMap<u64, Vec<IoEventEntry>>,
+    /// Backend new IO event registrations are offloaded to.
+    ioevent_backend: Option<Arc<dyn IoEventBackend>>,
+    /// Coalesced MMIO zones.
+    coalesced_zones: BTreeMap<IoRange, ()>,
+    /// Whether accesses are counted.
+    stats: bool,
+    /// Counters of pio accesses no registered device claims.
+    pio_unclaimed: Option<Arc<IoCounters>>,
+    /// Counters of mmio accesses no registered device claims.
+    mmio_unclaimed: Option<Arc<IoCounters>>,
+    /// Tracer called around each dispatched access.
+    tracer: Option<Arc<dyn IoTracer>>,
 }
 
 impl IoManager {
@@ -87,272 +515,3151 @@ impl IoManager {
     pub fn new() -> Self {
         IoManager::default()
     }
+
+    /// Set the device handling port I/O accesses to unclaimed ports.
+    ///
+    /// The fallback device covers the whole PIO bus: it is called with a zero
+    /// `base` and the accessed port as `offset`. With no fallback device,
+    /// unclaimed accesses fail with `Error::NoDevice`.
+    pub fn set_pio_fallback(&mut self, device: Option<Arc<dyn DeviceIo>>) {
+        self.pio_fallback = device;
+    }
+
+    /// Set the device handling memory-mapped I/O accesses to unclaimed addresses.
+    ///
+    /// The fallback device covers the whole MMIO bus: it is called with a zero
+    /// `base` and the accessed address as `offset`. With no fallback device,
+    /// unclaimed accesses fail with `Error::NoDevice`.
+    pub fn set_mmio_fallback(&mut self, device: Option<Arc<dyn DeviceIo>>) {
+        self.mmio_fallback = device;
+    }
+
+    /// Set how accesses running past the end of an IO range are handled.
+    ///
+    /// Accesses crossing a range end are rejected by default. When split, each
+    /// part of the access is handled by the device owning it, or by the
+    /// fallback device for unclaimed parts.
+    pub fn set_crossing_policy(&mut self, policy: CrossingPolicy) {
+        self.crossing = policy;
+    }
+
+    /// Enable or disable access statistics.
+    ///
+    /// When enabled, accesses are counted per registered range, accesses no
+    /// registered device claims being counted per bus. Counters are shared
+    /// with all the copies of the `IoManager`, e.g. the `SharedIoManager`
+    /// snapshots, and start from zero each time statistics are enabled.
+    pub fn set_stats_enabled(&mut self, enabled: bool) {
+        if enabled == self.stats {
+            return;
+        }
+        self.stats = enabled;
+        for entry in self.pio_bus.values_mut().chain(self.mmio_bus.values_mut()) {
+            entry.stats = Self::new_counters(enabled);
+        }
+        self.pio_unclaimed = Self::new_counters(self.stats);
+        self.mmio_unclaimed = Self::new_counters(self.stats);
+    }
+
+    // Return counters for a new range, if statistics are enabled.
+    fn new_counters(enabled: bool) -> Option<Arc<IoCounters>> {
+        if enabled {
+            Some(Arc::new(IoCounters::default()))
+        } else {
+            None
+        }
+    }
+
+    /// Accesses to unclaimed ports so far, if statistics are enabled.
+    pub fn pio_unclaimed_stats(&self) -> Option<IoStats> {
+        self.pio_unclaimed.as_ref().map(|stats| stats.snapshot())
+    }
+
+    /// Accesses to unclaimed MMIO addresses so far, if statistics are enabled.
+    pub fn mmio_unclaimed_stats(&self) -> Option<IoStats> {
+        self.mmio_unclaimed.as_ref().map(|stats| stats.snapshot())
+    }
+
+    // Return the counters of the accesses no registered device claims.
+    fn unclaimed(&self, addr: IoAddress) -> Option<&Arc<IoCounters>> {
+        match addr {
+            IoAddress::Pio(_) => self.pio_unclaimed.as_ref(),
+            IoAddress::Mmio(_) => self.mmio_unclaimed.as_ref(),
+        }
+    }
+
+    /// Set the tracer called before and after dispatching each access.
+    pub fn set_tracer(&mut self, tracer: Option<Arc<dyn IoTracer>>) {
+        self.tracer = tracer;
+    }
+
     /// Register a new device IO with its allocated resources.
     /// VMM is responsible for providing the allocated resources to virtual device.
     ///
+    /// Return a handle on the registered device, to be used to unregister it.
+    /// Nothing is registered if any of the IO ranges overlaps with an already
+    /// registered one.
+    ///
     /// # Arguments
     ///
     /// * `device`: device instance object to be registered
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
-    pub fn register_device_io(
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc. Resources
+    ///   held by a guard are committed once the device is registered.
+    pub fn register_device_io<R: DeviceResourcesRef>(
+        &mut self,
+        device: Arc<dyn DeviceIo>,
+        resources: R,
+    ) -> Result<DeviceHandle> {
+        let handle = self.register_resources(device, resources.resources())?;
+        resources.commit();
+        Ok(handle)
+    }
+
+    fn register_resources(
         &mut self,
         device: Arc<dyn DeviceIo>,
         resources: &[Resource],
-    ) -> Result<()> {
+    ) -> Result<DeviceHandle> {
+        let handle = DeviceHandle(self.next_handle);
+        let stats = self.stats;
+
+        // Empty ranges and ranges running past the end of their bus can't be
+        // checked for overlaps.
+        for res in resources.iter() {
+            match IoRange::from_resource(res) {
+                Some(range) if !range.is_valid() => return Err(Error::InvalidRange(res.clone())),
+                _ => {}
+            }
+        }
+
         // Register and mark device resources
         // The resources addresses being registered are sucessfully allocated before.
-        for (idx, res) in resources.iter().enumerate() {
-            match *res {
-                Resource::PioAddressRange { base, size } => {
-                    if self
-                        .pio_bus
-                        .insert(IoRange::new_pio_range(base, size), device.clone())
-                        .is_some()
-                    {
-                        // Unregister registered resources.
-                        self.unregister_device_io(&resources[0..idx])
-                            .expect("failed to unregister devices");
-
-                        return Err(Error::DeviceOverlap);
-                    }
-                }
-                Resource::MmioAddressRange { base, size } => {
-                    if self
-                        .mmio_bus
-                        .insert(IoRange::new_mmio_range(base, size), device.clone())
-                        .is_some()
-                    {
-                        // Unregister registered resources.
-                        self.unregister_device_io(&resources[0..idx])
-                            .expect("failed to unregister devices");
-
-                        return Err(Error::DeviceOverlap);
-                    }
+        let mut inserted: Vec<IoRange> = Vec::new();
+        for res in resources.iter() {
+            let range = match IoRange::from_resource(res) {
+                Some(range) => range,
+                None => continue,
+            };
+            let bus = self.bus_mut(range.base);
+            if let Some(existing) = Self::find_overlap(bus, &range) {
+                let err = Self::overlap_error(bus, res.clone(), existing);
+                // Unregister registered resources.
+                for range in inserted.iter() {
+                    self.bus_mut(range.base).remove(range);
                 }
-                _ => continue,
+
+                return Err(err);
             }
+            bus.insert(
+                range,
+                IoEntry {
+                    handle,
+                    device: device.clone(),
+                    base: range.base,
+                    offset: 0,
+                    policy: AccessPolicy::default(),
+                    enabled: true,
+                    stats: Self::new_counters(stats),
+                },
+            );
+            inserted.push(range);
         }
-        Ok(())
+
+        self.next_handle += 1;
+        self.devices.insert(
+            handle,
+            DeviceEntry {
+                device,
+                resources: resources.to_vec(),
+                alias_of: None,
+            },
+        );
+        Ok(handle)
     }
 
-    /// Unregister a device from `IoManager`, e.g. users specified removing.
-    /// VMM pre-fetches the resources e.g. dev.get_assigned_resources()
-    /// VMM is responsible for freeing the resources.
+    // Return the registered range conflicting with `range`, if any. Registered
+    // ranges never overlap each other, so only the closest neighbours on both
+    // sides of `range.base` need to be checked.
+    fn find_overlap<V>(bus: &BTreeMap<IoRange, V>, range: &IoRange) -> Option<IoRange> {
+        let prev = bus.range(..=range).next_back();
+        let next = bus.range((Excluded(range), Unbounded)).next();
+        prev.into_iter()
+            .chain(next)
+            .map(|(r, _)| *r)
+            .find(|r| r.overlaps(range))
+    }
+
+    // Build the error reporting `range` overlapping with the registered range
+    // `existing` of `bus`.
+    fn overlap_error(
+        bus: &BTreeMap<IoRange, IoEntry>,
+        range: Resource,
+        existing: IoRange,
+    ) -> Error {
+        Error::DeviceOverlap {
+            range,
+            existing: existing.to_resource(),
+            device: bus.get(&existing).and_then(|e| e.device.id().cloned()),
+        }
+    }
+
+    /// Register the devices of an IO region tree, the root region being
+    /// mapped at `base`.
     ///
-    /// # Arguments
+    /// The tree is flattened into the IO ranges where each device region is
+    /// visible. Each device is registered with the whole range of its region
+    /// as resource, and sees offsets relative to the start of its region even
+    /// if only part of it is visible. Nothing is registered if any of the
+    /// visible ranges overlaps with an already registered one.
     ///
-    /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
-    pub fn unregister_device_io(&mut self, resources: &[Resource]) -> Result<()> {
-        for res in resources.iter() {
-            match *res {
-                Resource::PioAddressRange { base, size } => {
-                    self.pio_bus.remove(&IoRange::new_pio_range(base, size));
-                }
-                Resource::MmioAddressRange { base, size } => {
-                    self.mmio_bus.remove(&IoRange::new_mmio_range(base, size));
-                }
-                _ => continue,
+    /// The tree is kept, so that unregistering one of its devices lets the
+    /// device regions it hid show through.
+    ///
+    /// Return the handles of the registered devices, highest priority first.
+    pub fn register_region(
+        &mut self,
+        root: &IoRegion,
+        base: IoAddress,
+    ) -> Result<Vec<DeviceHandle>> {
+        let invalid = |start: u128, size: u64| {
+            // Reported as close as the bus address space allows.
+            let max = (IoRange::bus_size(base) - 1) as u64;
+            let start = (u128::from(base.raw_value()) + start).min(u128::from(max)) as u64;
+            Error::InvalidRange(IoRange::new_range(base, start, size.min(max)).to_resource())
+        };
+        if IoRange::new_within_bus(base, 0, root.size()).is_none() {
+            return Err(invalid(0, root.size()));
+        }
+
+        let flat = root.flatten();
+        // Devices own their whole region, including hidden or clipped parts.
+        let mut leaf_ranges = Vec::new();
+        for leaf in flat.leaves.iter() {
+            match IoRange::new_within_bus(base, leaf.base, leaf.size) {
+                Some(range) => leaf_ranges.push(range),
+                None => return Err(invalid(leaf.base, leaf.size)),
+            }
+        }
+        let ranges: Vec<(IoRange, usize)> = flat
+            .ranges
+            .iter()
+            .map(|r| {
+                let range = IoRange::new_range(base, base.raw_value() + r.start, r.size);
+                (range, r.leaf)
+            })
+            .collect();
+        for (range, _) in ranges.iter() {
+            if let Some(existing) = Self::find_overlap(self.bus(base), range) {
+                return Err(Self::overlap_error(
+                    self.bus(base),
+                    range.to_resource(),
+                    existing,
+                ));
             }
         }
-        Ok(())
-    }
 
-    fn get_entry(&self, addr: IoAddress) -> Option<(&IoRange, &Arc<dyn DeviceIo>)> {
-        match addr {
-            IoAddress::Pio(a) => self
-                .pio_bus
-                .range(..=&IoRange::new_pio_range(a, 0))
-                .nth_back(0),
-            IoAddress::Mmio(a) => self
-                .mmio_bus
-                .range(..=&IoRange::new_mmio_range(a, 0))
-                .nth_back(0),
+        let mut handles = Vec::new();
+        for (leaf, range) in flat.leaves.iter().zip(leaf_ranges.iter()) {
+            let handle = DeviceHandle(self.next_handle);
+            self.next_handle += 1;
+            self.devices.insert(
+                handle,
+                DeviceEntry {
+                    device: leaf.device.clone(),
+                    resources: vec![range.to_resource()],
+                    alias_of: None,
+                },
+            );
+            handles.push(handle);
         }
+        for (range, leaf) in ranges {
+            let leaf_base = leaf_ranges[leaf].base.raw_value();
+            let entry = IoEntry {
+                handle: handles[leaf],
+                device: flat.leaves[leaf].device.clone(),
+                base: leaf_ranges[leaf].base,
+                offset: range.base.raw_value() - leaf_base,
+                policy: AccessPolicy::default(),
+                enabled: true,
+                stats: Self::new_counters(self.stats),
+            };
+            self.bus_mut(base).insert(range, entry);
+        }
+        self.regions.push(RegionEntry {
+            root: Arc::new(root.clone()),
+            base,
+            handles: handles.iter().cloned().map(Some).collect(),
+        });
+        Ok(handles)
     }
 
-    // Return the Device mapped `addr` and the base address.
-    fn get_device(&self, addr: IoAddress) -> Option<(&Arc<dyn DeviceIo>, IoAddress)> {
-        if let Some((range, dev)) = self.get_entry(addr) {
-            if (addr.raw_value() - range.base.raw_value()) < range.size.raw_value() {
-                return Some((dev, range.base));
+    // Map the parts of the `hidden` ranges where a device region of the tree
+    // `handle` belonged to shows through, now that `handle` is unregistered.
+    fn reveal_region(&mut self, handle: DeviceHandle, hidden: &[IoRange]) {
+        let idx = match self
+            .regions
+            .iter()
+            .position(|region| region.handles.contains(&Some(handle)))
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+        for leaf in self.regions[idx].handles.iter_mut() {
+            if *leaf == Some(handle) {
+                *leaf = None;
             }
         }
-        None
-    }
+        if self.regions[idx].handles.iter().all(Option::is_none) {
+            self.regions.remove(idx);
+            return;
+        }
 
-    /// A helper function handling PIO read command during VM exit.
-    /// The virtual device itself provides mutable ability and thead-safe protection.
-    ///
-    /// Return error if failed to get the device.
-    pub fn pio_read(&self, addr: u16, data: &mut [u8]) -> Result<()> {
-        if let Some((device, base)) = self.get_device(IoAddress::Pio(addr)) {
-            device.read(base, IoAddress::Pio(addr - (base.raw_value() as u16)), data);
-            Ok(())
-        } else {
-            Err(Error::NoDevice)
+        let region = self.regions[idx].clone();
+        let flat = region
+            .root
+            .flatten_with(&|leaf| region.handles[leaf].is_some());
+        let root_base = u128::from(region.base.raw_value());
+        for r in flat.ranges.iter() {
+            let leaf_handle = match region.handles[r.leaf] {
+                Some(leaf_handle) => leaf_handle,
+                None => continue,
+            };
+            // Ranges revealed next to visible ones get the same settings.
+            let (policy, enabled) = self
+                .bus(region.base)
+                .values()
+                .find(|io| io.handle == leaf_handle)
+                .map_or((AccessPolicy::default(), true), |io| {
+                    (io.policy, io.enabled)
+                });
+            // The whole tree was checked to fit in the bus when registered.
+            let leaf_base = (root_base + flat.leaves[r.leaf].base) as u64;
+            let start = root_base + u128::from(r.start);
+            let end = start + u128::from(r.size);
+            for range in hidden.iter() {
+                let hidden_start = u128::from(range.base.raw_value());
+                let hidden_end = hidden_start + u128::from(range.size.raw_value());
+                let (s, e) = (start.max(hidden_start), end.min(hidden_end));
+                if s >= e {
+                    continue;
+                }
+                let entry = IoEntry {
+                    handle: leaf_handle,
+                    device: flat.leaves[r.leaf].device.clone(),
+                    base: IoRange::new_range(region.base, leaf_base, 0).base,
+                    offset: s as u64 - leaf_base,
+                    policy,
+                    enabled,
+                    stats: Self::new_counters(self.stats),
+                };
+                let revealed = IoRange::new_range(region.base, s as u64, (e - s) as u64);
+                self.bus_mut(region.base).insert(revealed, entry);
+            }
         }
     }
 
-    /// A helper function handling PIO write command during VM exit.
-    /// The virtual device itself provides mutable ability and thead-safe protection.
+    /// Unregister a device from `IoManager`, e.g. users specified removing.
     ///
-    /// Return error if failed to get the device.
-    pub fn pio_write(&self, addr: u16, data: &[u8]) -> Result<()> {
-        if let Some((device, base)) = self.get_device(IoAddress::Pio(addr)) {
-            device.write(base, IoAddress::Pio(addr - (base.raw_value() as u16)), data);
-            Ok(())
-        } else {
-            Err(Error::NoDevice)
-        }
+    /// All the IO ranges the device was registered with are removed. The
+    /// device and its resources are given back, the VMM being responsible
+    /// for freeing the resources. For devices registered as part of an IO
+    /// region tree, the device regions they hid show through again.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle`: handle returned when registering the device.
+    pub fn unregister_device(
+        &mut self,
+        handle: DeviceHandle,
+    ) -> Result<(Arc<dyn DeviceIo>, Vec<Resource>)> {
+        let entry = self.devices.remove(&handle).ok_or(Error::NoDevice)?;
+        self.devices
+            .retain(|_, dev| dev.alias_of.is_none_or(|owner| owner != handle));
+        let hidden: Vec<IoRange> = self
+            .pio_bus
+            .iter()
+            .chain(self.mmio_bus.iter())
+            .filter(|(_, io)| io.handle == handle)
+            .map(|(range, _)| *range)
+            .collect();
+        let devices = &self.devices;
+        self.pio_bus
+            .retain(|_, io| devices.contains_key(&io.handle));
+        self.mmio_bus
+            .retain(|_, io| devices.contains_key(&io.handle));
+        self.reveal_region(handle, &hidden);
+        Ok((entry.device, entry.resources))
     }
 
-    /// A helper function handling MMIO read command during VM exit.
-    /// The virtual device itself provides mutable ability and thead-safe protection.
+    /// Register an alias forwarding the `alias` IO range to the window of the
+    /// same size at `target`, on the same bus.
     ///
-    /// Return error if failed to get the device.
-    pub fn mmio_read(&self, addr: u64, data: &mut [u8]) -> Result<()> {
-        if let Some((device, base)) = self.get_device(IoAddress::Mmio(addr)) {
-            device.read(base, IoAddress::Mmio(addr - base.raw_value()), data);
-            Ok(())
-        } else {
-            Err(Error::NoDevice)
+    /// The device handling `target` sees the same base and offsets whichever
+    /// window the guest accesses. The window at `target` must lie within a
+    /// single registered range. The alias enforces the access policy of the
+    /// target range, and is unregistered along with the device it forwards
+    /// to.
+    ///
+    /// Return a handle on the alias, to be used to unregister it.
+    pub fn register_alias(&mut self, alias: &Resource, target: IoAddress) -> Result<DeviceHandle> {
+        let range = match IoRange::from_resource(alias) {
+            Some(range) if range.base.is_pio() == target.is_pio() && range.is_valid() => range,
+            _ => return Err(Error::InvalidRange(alias.clone())),
+        };
+        let (target_range, target_entry) = self.get_entry(target).ok_or(Error::NoDevice)?;
+        let target_offset = target.raw_value() - target_range.base.raw_value();
+        if target_offset >= target_range.size.raw_value() {
+            return Err(Error::NoDevice);
+        }
+        if range.size.raw_value() > target_range.size.raw_value() - target_offset {
+            return Err(Error::InvalidRange(alias.clone()));
         }
+        if let Some(existing) = Self::find_overlap(self.bus(range.base), &range) {
+            return Err(Self::overlap_error(
+                self.bus(range.base),
+                alias.clone(),
+                existing,
+            ));
+        }
+
+        // Aliases of aliases forward to the device owning the target.
+        let owner = self.devices[&target_entry.handle]
+            .alias_of
+            .unwrap_or(target_entry.handle);
+        let handle = DeviceHandle(self.next_handle);
+        let entry = IoEntry {
+            handle,
+            device: target_entry.device.clone(),
+            base: target_entry.base,
+            offset: target_entry.offset + target_offset,
+            policy: target_entry.policy,
+            enabled: true,
+            stats: Self::new_counters(self.stats),
+        };
+        self.next_handle += 1;
+        self.devices.insert(
+            handle,
+            DeviceEntry {
+                device: entry.device.clone(),
+                resources: vec![alias.clone()],
+                alias_of: Some(owner),
+            },
+        );
+        self.bus_mut(range.base).insert(range, entry);
+        Ok(handle)
     }
 
-    /// A helper function handling MMIO write command during VM exit.
-    /// The virtual device itself provides mutable ability and thead-safe protection.
+    /// Enable or disable the decoding of all the IO ranges of a device, e.g.
+    /// following the PCI command register.
     ///
-    /// Return error if failed to get the device.
-    pub fn mmio_write(&self, addr: u64, data: &[u8]) -> Result<()> {
-        if let Some((device, base)) = self.get_device(IoAddress::Mmio(addr)) {
-            device.write(base, IoAddress::Mmio(addr - base.raw_value()), data);
-            Ok(())
-        } else {
-            Err(Error::NoDevice)
+    /// Disabled ranges stay registered, and still conflict with overlapping
+    /// ranges being registered, but accesses to them are handled as if no
+    /// device claimed them. The aliases of the device are enabled or disabled
+    /// along with it, while an alias can be toggled alone through its own
+    /// handle.
+    pub fn set_enabled(&mut self, handle: DeviceHandle, enabled: bool) -> Result<()> {
+        if !self.devices.contains_key(&handle) {
+            return Err(Error::NoDevice);
+        }
+        let devices = &self.devices;
+        for entry in self
+            .pio_bus
+            .values_mut()
+            .chain(self.mmio_bus.values_mut())
+            .filter(|entry| {
+                entry.handle == handle
+                    || devices.get(&entry.handle).and_then(|dev| dev.alias_of) == Some(handle)
+            })
+        {
+            entry.enabled = enabled;
         }
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Mutex;
-
-    const PIO_ADDRESS_SIZE: u16 = 4;
-    const PIO_ADDRESS_BASE: u16 = 0x40;
-    const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
-    const MMIO_ADDRESS_BASE: u64 = 0x1234_5678;
-    const LEGACY_IRQ: u32 = 4;
-    const CONFIG_DATA: u32 = 0x1234;
 
-    struct DummyDevice {
-        config: Mutex<u32>,
-    }
+    /// Move an IO range of a registered device to a new place on the same bus,
+    /// e.g. when the guest reprograms a PCI BAR.
+    ///
+    /// The range keeps its access policy and enabled state, and the device
+    /// sees the new base with unchanged offsets, through its aliases too.
+    /// `new` must have the size of `old` and fit in the bus. If it overlaps
+    /// with another registered range, the device is left mapped at `old`.
+    /// IO events and coalesced zones are not moved along with the range.
+    /// Relocating a device of an IO region tree detaches the tree, its other
+    /// devices then being unregistered as plain devices.
+    ///
+    /// Through `SharedIoManager::update`, vCPUs see either the old or the new
+    /// mapping, never none of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle`: handle returned when registering the device.
+    /// * `old`: PIO or MMIO range the device currently owns.
+    /// * `new`: range to move it to, on the same bus as `old`.
+    pub fn relocate_range(
+        &mut self,
+        handle: DeviceHandle,
+        old: &Resource,
+        new: &Resource,
+    ) -> Result<()> {
+        self.range_entry_mut(handle, old)?;
+        let old_range = IoRange::from_resource(old).ok_or(Error::NoDevice)?;
+        let new_range = match IoRange::from_resource(new) {
+            Some(range)
+                if range.base.is_pio() == old_range.base.is_pio()
+                    && range.size.raw_value() == old_range.size.raw_value()
+                    && range.fits_bus() =>
+            {
+                range
+            }
+            _ => return Err(Error::InvalidRange(new.clone())),
+        };
 
-    impl DummyDevice {
-        fn new(config: u32) -> Self {
-            DummyDevice {
-                config: Mutex::new(config),
+        let bus = self.bus_mut(old_range.base);
+        let mut entry = bus.remove(&old_range).ok_or(Error::NoDevice)?;
+        if let Some(existing) = Self::find_overlap(bus, &new_range) {
+            let err = Self::overlap_error(bus, new.clone(), existing);
+            bus.insert(old_range, entry);
+            return Err(err);
+        }
+        // Aliases keep forwarding to the same window of their target, while
+        // the aliases of a relocated device range follow it so that the
+        // device sees the same base through all of them.
+        if self.devices[&handle].alias_of.is_none() {
+            entry.base = new_range.base;
+            let devices = &self.devices;
+            let bus = match old_range.base {
+                IoAddress::Pio(_) => &mut self.pio_bus,
+                IoAddress::Mmio(_) => &mut self.mmio_bus,
+            };
+            for alias in bus.values_mut().filter(|io| {
+                io.base == old_range.base
+                    && devices.get(&io.handle).and_then(|dev| dev.alias_of) == Some(handle)
+            }) {
+                alias.base = new_range.base;
             }
         }
+        // The tree of a relocated device region no longer describes the bus.
+        self.regions
+            .retain(|region| !region.handles.contains(&Some(handle)));
+        let device = self.devices.get_mut(&handle).ok_or(Error::NoDevice)?;
+        for res in device.resources.iter_mut().filter(|res| *res == old) {
+            *res = new.clone();
+        }
+        self.bus_mut(new_range.base).insert(new_range, entry);
+        Ok(())
     }
 
-    impl DeviceIo for DummyDevice {
-        fn read(&self, _base: IoAddress, _offset: IoAddress, data: &mut [u8]) {
-            if data.len() > 4 {
-                return;
-            }
-            for (idx, iter) in data.iter_mut().enumerate() {
-                let config = self.config.lock().expect("failed to acquire lock");
-                *iter = (*config >> (idx * 8) & 0xff) as u8;
+    /// Apply all the operations staged in `transaction`, in order, or none of
+    /// them.
+    ///
+    /// Each operation sees the effect of the previous successful ones. When
+    /// any operation fails, the `IoManager` is left untouched and the error
+    /// lists every failed operation. Transactions don't stage IO event
+    /// changes, so no IO event backend has anything to undo.
+    ///
+    /// Return the handles of the registered devices, in the order their
+    /// registration was staged.
+    pub fn commit(&mut self, transaction: IoTransaction) -> Result<Vec<DeviceHandle>> {
+        let mut staged = self.clone();
+        let mut handles = Vec::new();
+        let mut failures = Vec::new();
+        for (index, op) in transaction.ops.into_iter().enumerate() {
+            let result = match op {
+                Operation::Register { device, resources } => staged
+                    .register_device_io(device, &resources)
+                    .map(|handle| handles.push(handle)),
+                Operation::Unregister(handle) => staged.unregister_device(handle).map(|_| ()),
+                Operation::Relocate { handle, old, new } => {
+                    staged.relocate_range(handle, &old, &new)
+                }
+            };
+            if let Err(e) = result {
+                failures.push((index, e));
             }
         }
 
-        fn write(&self, _base: IoAddress, _offset: IoAddress, data: &[u8]) {
-            let mut config = self.config.lock().expect("failed to acquire lock");
-            *config = u32::from(data[0]) & 0xff;
+        if !failures.is_empty() {
+            return Err(Error::Transaction { failures });
+        }
+        *self = staged;
+        Ok(handles)
+    }
+
+    /// Set the access policy of an IO range of a registered device.
+    ///
+    /// The aliases forwarding to the range enforce the new policy too.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle`: handle returned when registering the device.
+    /// * `range`: PIO or MMIO range the device was registered with.
+    /// * `policy`: access policy enforced on accesses to `range`.
+    pub fn set_access_policy(
+        &mut self,
+        handle: DeviceHandle,
+        range: &Resource,
+        policy: AccessPolicy,
+    ) -> Result<()> {
+        let entry = self.range_entry_mut(handle, range)?;
+        entry.policy = policy;
+        let (base, start) = (entry.base, entry.offset);
+        let end = start + IoRange::from_resource(range).map_or(0, |r| r.size.raw_value());
+        let devices = &self.devices;
+        let bus = match base {
+            IoAddress::Pio(_) => &mut self.pio_bus,
+            IoAddress::Mmio(_) => &mut self.mmio_bus,
+        };
+        for alias in bus.values_mut().filter(|io| {
+            io.base == base
+                && (start..end).contains(&io.offset)
+                && devices.get(&io.handle).and_then(|dev| dev.alias_of) == Some(handle)
+        }) {
+            alias.policy = policy;
+        }
+        Ok(())
+    }
+
+    // Return the bus entry of the IO range `res` owned by the device `handle`.
+    fn range_entry_mut(&mut self, handle: DeviceHandle, res: &Resource) -> Result<&mut IoEntry> {
+        if !self.devices.contains_key(&handle) {
+            return Err(Error::NoDevice);
+        }
+        let range = IoRange::from_resource(res).ok_or_else(|| Error::RangeNotFound(res.clone()))?;
+        match self.bus_mut(range.base).range_mut(range..=range).next() {
+            Some((r, entry))
+                if entry.handle == handle && r.size.raw_value() == range.size.raw_value() =>
+            {
+                Ok(entry)
+            }
+            _ => Err(Error::RangeNotFound(res.clone())),
+        }
+    }
+
+    /// Set the backend new IO event registrations are offloaded to.
+    ///
+    /// Registrations made before setting the backend are not offloaded.
+    pub fn set_ioevent_backend(&mut self, backend: Option<Arc<dyn IoEventBackend>>) {
+        self.ioevent_backend = backend;
+    }
+
+    /// Register an IO event: guest writes matching `event` only signal
+    /// `notifier` and are not dispatched to any device.
+    ///
+    /// The registration is offloaded to the IO event backend, if any. As with
+    /// KVM, events colliding with a registered one are rejected, see
+    /// [IoEvent::collides](../ioevent/struct.IoEvent.html#method.collides).
+    pub fn register_ioevent(
+        &mut self,
+        event: IoEvent,
+        notifier: Arc<dyn IoEventNotifier>,
+    ) -> Result<()> {
+        if !event.is_valid() {
+            return Err(Error::InvalidIoEvent(event));
+        }
+        let backend = self.ioevent_backend.clone();
+        let events = self.ioevents_mut(event.addr);
+        if let Some(entries) = events.get(&event.addr.raw_value()) {
+            if entries.iter().any(|entry| entry.event.collides(&event)) {
+                return Err(Error::IoEventExists(event));
+            }
+        }
+        if let Some(backend) = backend.as_ref() {
+            backend
+                .register_ioevent(&event, &notifier)
+                .map_err(Error::IoEventBackend)?;
+        }
+        events
+            .entry(event.addr.raw_value())
+            .or_default()
+            .push(IoEventEntry {
+                event,
+                notifier,
+                backend,
+            });
+        Ok(())
+    }
+
+    /// Unregister an IO event, also from the backend it was offloaded to.
+    ///
+    /// Return the notifier the event was registered with.
+    pub fn unregister_ioevent(&mut self, event: &IoEvent) -> Result<Arc<dyn IoEventNotifier>> {
+        let events = self.ioevents_mut(event.addr);
+        let entries = events
+            .get_mut(&event.addr.raw_value())
+            .ok_or(Error::IoEventNotFound(*event))?;
+        let idx = entries
+            .iter()
+            .position(|entry| entry.event == *event)
+            .ok_or(Error::IoEventNotFound(*event))?;
+        if let Some(backend) = entries[idx].backend.as_ref() {
+            backend
+                .unregister_ioevent(event, &entries[idx].notifier)
+                .map_err(Error::IoEventBackend)?;
+        }
+        let entry = entries.remove(idx);
+        if entries.is_empty() {
+            events.remove(&event.addr.raw_value());
+        }
+        Ok(entry.notifier)
+    }
+
+    // Undo the backend side effects of the IO event changes made since
+    // `base`: offloaded registrations are removed and offloaded
+    // unregistrations are registered again, as far as the backends allow.
+    fn rollback_ioevents(&self, base: &IoManager) {
+        let added = |from: &BTreeMap<u64, Vec<IoEventEntry>>,
+                     to: &BTreeMap<u64, Vec<IoEventEntry>>| {
+            let mut added = Vec::new();
+            for (key, entries) in to.iter() {
+                for entry in entries.iter() {
+                    let known = from.get(key).into_iter().flatten().any(|old| {
+                        old.event == entry.event && Arc::ptr_eq(&old.notifier, &entry.notifier)
+                    });
+                    if !known {
+                        added.push(entry.clone());
+                    }
+                }
+            }
+            added
+        };
+        for (from, to) in [
+            (&base.pio_ioevents, &self.pio_ioevents),
+            (&base.mmio_ioevents, &self.mmio_ioevents),
+        ]
+        .iter()
+        {
+            for entry in added(from, to) {
+                if let Some(backend) = entry.backend.as_ref() {
+                    let _ = backend.unregister_ioevent(&entry.event, &entry.notifier);
+                }
+            }
+            for entry in added(to, from) {
+                if let Some(backend) = entry.backend.as_ref() {
+                    let _ = backend.register_ioevent(&entry.event, &entry.notifier);
+                }
+            }
+        }
+    }
+
+    fn ioevents_mut(&mut self, addr: IoAddress) -> &mut BTreeMap<u64, Vec<IoEventEntry>> {
+        match addr {
+            IoAddress::Pio(_) => &mut self.pio_ioevents,
+            IoAddress::Mmio(_) => &mut self.mmio_ioevents,
+        }
+    }
+
+    // Signal the notifier of the IO event matching a write, if any.
+    fn notify_ioevent(&self, addr: IoAddress, data: &[u8]) -> Option<Result<()>> {
+        let events = match addr {
+            IoAddress::Pio(_) => &self.pio_ioevents,
+            IoAddress::Mmio(_) => &self.mmio_ioevents,
+        };
+        let entry = events
+            .get(&addr.raw_value())?
+            .iter()
+            .find(|entry| entry.event.matches(addr, data))?;
+        Some(entry.notifier.notify().map_err(|error| {
+            Error::IoEventNotify {
+                addr,
+                device: self
+                    .get_device(addr)
+                    .and_then(|target| target.device.id().cloned()),
+                error,
+            }
+        }))
+    }
+
+    /// Declare a coalesced MMIO zone.
+    ///
+    /// Writes to coalesced zones are expected to be batched in a coalesced
+    /// ring by the hypervisor, and replayed with `drain_coalesced`.
+    pub fn register_coalesced_zone(&mut self, base: u64, size: u64) -> Result<()> {
+        let zone = IoRange::new_mmio_range(base, size);
+        if !zone.is_valid() {
+            return Err(Error::InvalidCoalescedZone { base, size });
+        }
+        if let Some(existing) = Self::find_overlap(&self.coalesced_zones, &zone) {
+            return Err(Error::CoalescedZoneOverlap {
+                base: existing.base.raw_value(),
+                size: existing.size.raw_value(),
+            });
+        }
+        self.coalesced_zones.insert(zone, ());
+        Ok(())
+    }
+
+    /// Remove a coalesced MMIO zone.
+    pub fn unregister_coalesced_zone(&mut self, base: u64, size: u64) -> Result<()> {
+        let zone = IoRange::new_mmio_range(base, size);
+        match self.coalesced_zones.get_key_value(&zone) {
+            Some((z, _)) if z.size.raw_value() == size => {
+                self.coalesced_zones.remove(&zone);
+                Ok(())
+            }
+            _ => Err(Error::CoalescedZoneNotFound { base, size }),
+        }
+    }
+
+    // Check whether the MMIO write of `len` bytes at `addr` is coalesced.
+    fn is_coalesced(&self, addr: u64, len: usize) -> bool {
+        self.coalesced_zones
+            .range(..=IoRange::new_mmio_range(addr, 0))
+            .next_back()
+            .is_some_and(|(zone, _)| {
+                let offset = addr - zone.base.raw_value();
+                offset < zone.size.raw_value() && len as u64 <= zone.size.raw_value() - offset
+            })
+    }
+
+    /// Replay the writes pending in a coalesced MMIO ring, in order, as MMIO
+    /// write VM exits.
+    ///
+    /// Return the number of replayed writes. On error, the failing write is
+    /// consumed and the following ones are left pending in the ring.
+    pub fn drain_coalesced<R: CoalescedRing + ?Sized>(&self, ring: &mut R) -> Result<usize> {
+        let mut count = 0;
+        while let Some(entry) = ring.pop() {
+            if !self.is_coalesced(entry.addr(), entry.data().len()) {
+                return Err(Error::NotCoalesced(IoAddress::Mmio(entry.addr())));
+            }
+            self.mmio_write(entry.addr(), entry.data())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn bus(&self, addr: IoAddress) -> &BTreeMap<IoRange, IoEntry> {
+        match addr {
+            IoAddress::Pio(_) => &self.pio_bus,
+            IoAddress::Mmio(_) => &self.mmio_bus,
+        }
+    }
+
+    fn bus_mut(&mut self, addr: IoAddress) -> &mut BTreeMap<IoRange, IoEntry> {
+        match addr {
+            IoAddress::Pio(_) => &mut self.pio_bus,
+            IoAddress::Mmio(_) => &mut self.mmio_bus,
+        }
+    }
+
+    fn get_entry(&self, addr: IoAddress) -> Option<(&IoRange, &IoEntry)> {
+        match addr {
+            IoAddress::Pio(a) => self
+                .pio_bus
+                .range(..=&IoRange::new_pio_range(a, 0))
+                .nth_back(0),
+            IoAddress::Mmio(a) => self
+                .mmio_bus
+                .range(..=&IoRange::new_mmio_range(a, 0))
+                .nth_back(0),
+        }
+    }
+
+    // Return the Device mapped `addr` and the base address.
+    fn get_device(&self, addr: IoAddress) -> Option<Target<'_>> {
+        if let Some((range, entry)) = self.get_entry(addr) {
+            let offset = addr.raw_value() - range.base.raw_value();
+            if entry.enabled && offset < range.size.raw_value() {
+                return Some(Target {
+                    handle: Some(entry.handle),
+                    device: &entry.device,
+                    base: entry.base,
+                    start: range.base.raw_value(),
+                    offset: entry.offset,
+                    policy: entry.policy,
+                    remaining: range.size.raw_value() - offset,
+                    stats: entry.stats.as_ref(),
+                });
+            }
+        }
+        None
+    }
+
+    // Return the device handling an access to `addr`, falling back to the bus
+    // fallback device when no registered device claims it.
+    fn resolve(&self, addr: IoAddress) -> Option<Target<'_>> {
+        self.get_device(addr).or_else(|| {
+            let fallback = match addr {
+                IoAddress::Pio(_) => self.pio_fallback.as_ref(),
+                IoAddress::Mmio(_) => self.mmio_fallback.as_ref(),
+            };
+            // The fallback device claims the gap up to the next range.
+            let key = IoRange {
+                base: addr,
+                size: IoSize::Mmio(0),
+            };
+            let remaining = match self.bus(addr).range((Excluded(key), Unbounded)).next() {
+                Some((next, _)) => next.base.raw_value() - addr.raw_value(),
+                // Saturated, accesses being shorter than the whole bus anyway.
+                None => (IoRange::bus_size(addr) - u128::from(addr.raw_value()))
+                    .min(u128::from(u64::MAX)) as u64,
+            };
+            fallback.map(|device| Target {
+                handle: None,
+                device,
+                base: addr.with_raw_value(0),
+                start: 0,
+                offset: 0,
+                policy: AccessPolicy::default(),
+                remaining,
+                stats: self.unclaimed(addr),
+            })
+        })
+    }
+
+    // Split an access of `len` bytes at `addr`, running past the end of the
+    // range of `first`, into accesses to the successive ranges it covers.
+    fn split_crossing<'a>(
+        &'a self,
+        addr: IoAddress,
+        first: Target<'a>,
+        len: usize,
+    ) -> Result<Vec<Access<'a>>> {
+        // Parts past the end of the bus would wrap around to its start.
+        if self.crossing == CrossingPolicy::Reject
+            || u128::from(addr.raw_value()) + len as u128 > IoRange::bus_size(addr)
+        {
+            return Err(Error::RangeCrossing {
+                addr,
+                len,
+                device: first.device.id().cloned(),
+            });
+        }
+
+        let mut accesses = Vec::new();
+        let mut target = first;
+        let mut pos = 0;
+        loop {
+            let part_addr = addr.with_raw_value(addr.raw_value() + pos as u64);
+            let part_len = target.remaining.min((len - pos) as u64) as usize;
+            if part_len == 0 {
+                return Err(Error::RangeCrossing {
+                    addr,
+                    len,
+                    device: target.device.id().cloned(),
+                });
+            }
+            let widths = target.plan(part_addr, part_len)?;
+            accesses.push(Access {
+                target,
+                pos,
+                len: part_len,
+                widths,
+            });
+            pos += part_len;
+            if pos == len {
+                return Ok(accesses);
+            }
+            target = self
+                .resolve(addr.with_raw_value(addr.raw_value() + pos as u64))
+                .ok_or(Error::NoDevice)?;
+        }
+    }
+
+    // Resolve the device handling an access, counting the access as unclaimed
+    // if there is none.
+    fn resolve_or_count(
+        &self,
+        dir: IoDirection,
+        addr: IoAddress,
+        len: usize,
+    ) -> Result<Target<'_>> {
+        self.resolve(addr).ok_or_else(|| {
+            if let Some(stats) = self.unclaimed(addr) {
+                stats.record(dir, len);
+            }
+            Error::NoDevice
+        })
+    }
+
+    // Accesses are fully checked against the ranges and their access policies
+    // before calling into any device, so that invalid accesses have no side
+    // effects.
+    fn read(&self, addr: IoAddress, data: &mut [u8]) -> Result<()> {
+        let tracer = match self.tracer {
+            Some(ref tracer) => tracer,
+            None => return self.dispatch_read(addr, data),
+        };
+        let (handle, device) = self.traced_device(addr);
+        tracer.before(&IoAccess {
+            dir: IoDirection::Read,
+            addr,
+            data,
+            handle,
+            device,
+        });
+        let result = self.dispatch_read(addr, data);
+        let access = IoAccess {
+            dir: IoDirection::Read,
+            addr,
+            data,
+            handle,
+            device,
+        };
+        tracer.after(&access, &result);
+        result
+    }
+
+    fn write(&self, addr: IoAddress, data: &[u8]) -> Result<()> {
+        let tracer = match self.tracer {
+            Some(ref tracer) => tracer,
+            None => return self.dispatch_write(addr, data),
+        };
+        let (handle, device) = self.traced_device(addr);
+        let access = IoAccess {
+            dir: IoDirection::Write,
+            addr,
+            data,
+            handle,
+            device,
+        };
+        tracer.before(&access);
+        let result = self.dispatch_write(addr, data);
+        tracer.after(&access, &result);
+        result
+    }
+
+    // Return the device an access to `addr` resolves to, for tracing.
+    fn traced_device(&self, addr: IoAddress) -> (Option<DeviceHandle>, Option<&Arc<dyn DeviceIo>>) {
+        match self.resolve(addr) {
+            Some(target) => (target.handle, Some(target.device)),
+            None => (None, None),
+        }
+    }
+
+    fn dispatch_read(&self, addr: IoAddress, data: &mut [u8]) -> Result<()> {
+        let target = self.resolve_or_count(IoDirection::Read, addr, data.len())?;
+        if target.remaining >= data.len() as u64 {
+            let widths = target.plan(addr, data.len())?;
+            return target.read(addr, data, widths);
+        }
+        for access in self.split_crossing(addr, target, data.len())? {
+            let part_addr = addr.with_raw_value(addr.raw_value() + access.pos as u64);
+            let part = &mut data[access.pos..access.pos + access.len];
+            access.target.read(part_addr, part, access.widths)?;
+        }
+        Ok(())
+    }
+
+    fn dispatch_write(&self, addr: IoAddress, data: &[u8]) -> Result<()> {
+        if let Some(ret) = self.notify_ioevent(addr, data) {
+            if let Some(stats) = self.get_device(addr).and_then(|target| target.stats) {
+                stats.record(IoDirection::Write, data.len());
+            }
+            return ret;
+        }
+        let target = self.resolve_or_count(IoDirection::Write, addr, data.len())?;
+        if target.remaining >= data.len() as u64 {
+            let widths = target.plan(addr, data.len())?;
+            return target.write(addr, data, widths);
+        }
+        for access in self.split_crossing(addr, target, data.len())? {
+            let part_addr = addr.with_raw_value(addr.raw_value() + access.pos as u64);
+            let part = &data[access.pos..access.pos + access.len];
+            access.target.write(part_addr, part, access.widths)?;
+        }
+        Ok(())
+    }
+
+    /// A helper function handling PIO read command during VM exit.
+    /// The virtual device itself provides mutable ability and thead-safe protection.
+    ///
+    /// Return error if failed to get the device, with no fallback device set,
+    /// if the access violates the range access or crossing policy or if the
+    /// device failed to handle the access.
+    pub fn pio_read(&self, addr: u16, data: &mut [u8]) -> Result<()> {
+        self.read(IoAddress::Pio(addr), data)
+    }
+
+    /// A helper function handling PIO write command during VM exit.
+    /// The virtual device itself provides mutable ability and thead-safe protection.
+    ///
+    /// Return error if failed to get the device, with no fallback device set,
+    /// if the access violates the range access or crossing policy or if the
+    /// device failed to handle the access.
+    pub fn pio_write(&self, addr: u16, data: &[u8]) -> Result<()> {
+        self.write(IoAddress::Pio(addr), data)
+    }
+
+    /// A helper function handling MMIO read command during VM exit.
+    /// The virtual device itself provides mutable ability and thead-safe protection.
+    ///
+    /// Return error if failed to get the device, with no fallback device set,
+    /// if the access violates the range access or crossing policy or if the
+    /// device failed to handle the access.
+    pub fn mmio_read(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        self.read(IoAddress::Mmio(addr), data)
+    }
+
+    /// A helper function handling MMIO write command during VM exit.
+    /// The virtual device itself provides mutable ability and thead-safe protection.
+    ///
+    /// Return error if failed to get the device, with no fallback device set,
+    /// if the access violates the range access or crossing policy or if the
+    /// device failed to handle the access.
+    pub fn mmio_write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.write(IoAddress::Mmio(addr), data)
+    }
+
+    /// Iterate over the registered PIO ranges, in address order.
+    pub fn pio_ranges(&self) -> impl Iterator<Item = MappedRange<'_>> {
+        self.pio_bus
+            .iter()
+            .map(move |(range, entry)| self.mapped_range(range, entry))
+    }
+
+    /// Iterate over the registered MMIO ranges, in address order.
+    pub fn mmio_ranges(&self) -> impl Iterator<Item = MappedRange<'_>> {
+        self.mmio_bus
+            .iter()
+            .map(move |(range, entry)| self.mapped_range(range, entry))
+    }
+
+    /// Return the registered PIO range containing the port `addr`, without
+    /// performing any IO.
+    ///
+    /// Disabled ranges are returned as well, see `MappedRange::enabled`.
+    pub fn pio_lookup(&self, addr: u16) -> Option<MappedRange<'_>> {
+        self.lookup(IoAddress::Pio(addr))
+    }
+
+    /// Return the registered MMIO range containing the address `addr`,
+    /// without performing any IO.
+    ///
+    /// Disabled ranges are returned as well, see `MappedRange::enabled`.
+    pub fn mmio_lookup(&self, addr: u64) -> Option<MappedRange<'_>> {
+        self.lookup(IoAddress::Mmio(addr))
+    }
+
+    fn lookup(&self, addr: IoAddress) -> Option<MappedRange<'_>> {
+        self.get_entry(addr)
+            .filter(|(range, _)| addr.raw_value() - range.base.raw_value() < range.size.raw_value())
+            .map(|(range, entry)| self.mapped_range(range, entry))
+    }
+
+    fn mapped_range<'a>(&'a self, range: &IoRange, entry: &'a IoEntry) -> MappedRange<'a> {
+        MappedRange {
+            range: range.to_resource(),
+            handle: entry.handle,
+            device: &entry.device,
+            alias_of: self.devices[&entry.handle].alias_of,
+            enabled: entry.enabled,
+            stats: entry.stats.as_ref().map(|stats| stats.snapshot()),
+        }
+    }
+}
+
+/// Human readable map of the registered IO ranges, one range per line.
+impl fmt::Display for IoManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "pio:")?;
+        for range in self.pio_ranges() {
+            writeln!(f, "  {}", range)?;
+        }
+        writeln!(f, "mmio:")?;
+        for range in self.mmio_ranges() {
+            writeln!(f, "  {}", range)?;
+        }
+        Ok(())
+    }
+}
+
+// Device handling an access, as resolved by `IoManager`.
+struct Target<'a> {
+    // Registered device, `None` for fallback devices.
+    handle: Option<DeviceHandle>,
+    device: &'a Arc<dyn DeviceIo>,
+    base: IoAddress,
+    // Start of the range and offset passed to the device for it.
+    start: u64,
+    offset: u64,
+    policy: AccessPolicy,
+    // Number of bytes from the resolved address to the end of the range.
+    remaining: u64,
+    stats: Option<&'a Arc<IoCounters>>,
+}
+
+impl<'a> Target<'a> {
+    // Check an access of `len` bytes at `addr` against the access policy.
+    // Return the widths of the accesses to split it into, or `None` if it
+    // can be forwarded to the device as is.
+    fn plan(&self, addr: IoAddress, len: usize) -> Result<Option<Vec<usize>>> {
+        if self.policy.allows(addr.raw_value(), len) {
+            return Ok(None);
+        }
+        match self.policy.on_violation {
+            ViolationAction::PassThrough => Ok(None),
+            ViolationAction::Reject => Err(self.invalid_access(addr, len)),
+            ViolationAction::Split => self
+                .policy
+                .split(addr.raw_value(), len)
+                .map(Some)
+                .ok_or_else(|| self.invalid_access(addr, len)),
+        }
+    }
+
+    fn invalid_access(&self, addr: IoAddress, len: usize) -> Error {
+        Error::InvalidAccess {
+            addr,
+            len,
+            device: self.device.id().cloned(),
+        }
+    }
+
+    fn offset(&self, addr: IoAddress) -> IoAddress {
+        addr.with_raw_value(self.offset + (addr.raw_value() - self.start))
+    }
+
+    fn read(&self, addr: IoAddress, data: &mut [u8], widths: Option<Vec<usize>>) -> Result<()> {
+        if let Some(stats) = self.stats {
+            stats.record(IoDirection::Read, data.len());
+        }
+        let widths = match widths {
+            Some(widths) => widths,
+            None => return self.read_one(addr, data),
+        };
+        let mut pos = 0;
+        for width in widths {
+            let chunk_addr = addr.with_raw_value(addr.raw_value() + pos as u64);
+            self.read_one(chunk_addr, &mut data[pos..pos + width])?;
+            pos += width;
+        }
+        Ok(())
+    }
+
+    fn write(&self, addr: IoAddress, data: &[u8], widths: Option<Vec<usize>>) -> Result<()> {
+        if let Some(stats) = self.stats {
+            stats.record(IoDirection::Write, data.len());
+        }
+        let widths = match widths {
+            Some(widths) => widths,
+            None => return self.write_one(addr, data),
+        };
+        let mut pos = 0;
+        for width in widths {
+            let chunk_addr = addr.with_raw_value(addr.raw_value() + pos as u64);
+            self.write_one(chunk_addr, &data[pos..pos + width])?;
+            pos += width;
+        }
+        Ok(())
+    }
+
+    fn read_one(&self, addr: IoAddress, data: &mut [u8]) -> Result<()> {
+        self.device
+            .read(self.base, self.offset(addr), data)
+            .map_err(|error| Error::DeviceIo {
+                addr,
+                device: self.device.id().cloned(),
+                error,
+            })
+    }
+
+    fn write_one(&self, addr: IoAddress, data: &[u8]) -> Result<()> {
+        self.device
+            .write(self.base, self.offset(addr), data)
+            .map_err(|error| Error::DeviceIo {
+                addr,
+                device: self.device.id().cloned(),
+                error,
+            })
+    }
+}
+
+// Part of an access crossing IO ranges, handled by `target`.
+struct Access<'a> {
+    target: Target<'a>,
+    // Position and length of the part within the access data.
+    pos: usize,
+    len: usize,
+    // Widths of the accesses to split the part into, if any.
+    widths: Option<Vec<usize>>,
+}
+
+// Callback reporting unclaimed accesses handled by `OpenBus`.
+type UnclaimedObserver = Box<dyn Fn(IoDirection, IoAddress, usize) + Send + Sync>;
+
+// Operation staged in an `IoTransaction`.
+enum Operation {
+    Register {
+        device: Arc<dyn DeviceIo>,
+        resources: Vec<Resource>,
+    },
+    Unregister(DeviceHandle),
+    Relocate {
+        handle: DeviceHandle,
+        old: Resource,
+        new: Resource,
+    },
+}
+
+/// Batch of device registration updates, committed all-or-nothing to an
+/// `IoManager` with `IoManager::commit`.
+///
+/// Operations are only staged here and are validated when committed.
+#[derive(Default)]
+pub struct IoTransaction {
+    ops: Vec<Operation>,
+}
+
+impl IoTransaction {
+    /// Create an empty transaction.
+    pub fn new() -> Self {
+        IoTransaction::default()
+    }
+
+    /// Stage the registration of `device` with its allocated resources, as
+    /// done by `IoManager::register_device_io`.
+    pub fn register_device_io(
+        &mut self,
+        device: Arc<dyn DeviceIo>,
+        resources: &[Resource],
+    ) -> &mut Self {
+        self.ops.push(Operation::Register {
+            device,
+            resources: resources.to_vec(),
+        });
+        self
+    }
+
+    /// Stage the removal of a registered device, as done by
+    /// `IoManager::unregister_device`.
+    pub fn unregister_device(&mut self, handle: DeviceHandle) -> &mut Self {
+        self.ops.push(Operation::Unregister(handle));
+        self
+    }
+
+    /// Stage moving an IO range of a registered device, as done by
+    /// `IoManager::relocate_range`.
+    pub fn relocate_range(
+        &mut self,
+        handle: DeviceHandle,
+        old: &Resource,
+        new: &Resource,
+    ) -> &mut Self {
+        self.ops.push(Operation::Relocate {
+            handle,
+            old: old.clone(),
+            new: new.clone(),
+        });
+        self
+    }
+}
+
+/// Fallback device emulating an unpopulated bus.
+///
+/// Reads return all ones and writes are dropped, as seen by a guest accessing
+/// a port or an address nothing decodes. Unclaimed accesses are counted and
+/// can be reported to an observer, e.g. for logging.
+#[derive(Default)]
+pub struct OpenBus {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    observer: Option<UnclaimedObserver>,
+}
+
+impl OpenBus {
+    /// Create an `OpenBus` fallback device.
+    pub fn new() -> Self {
+        OpenBus::default()
+    }
+
+    /// Create an `OpenBus` fallback device calling `observer` with the
+    /// direction, address and length of every unclaimed access.
+    pub fn with_observer<F>(observer: F) -> Self
+    where
+        F: Fn(IoDirection, IoAddress, usize) + Send + Sync + 'static,
+    {
+        OpenBus {
+            observer: Some(Box::new(observer)),
+            ..Default::default()
+        }
+    }
+
+    /// Number of unclaimed reads handled so far.
+    pub fn unclaimed_reads(&self) -> u64 {
+        self.reads.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Number of unclaimed writes handled so far.
+    pub fn unclaimed_writes(&self) -> u64 {
+        self.writes.load(AtomicOrdering::Relaxed)
+    }
+
+    fn observe(&self, dir: IoDirection, addr: IoAddress, len: usize) {
+        if let Some(observer) = self.observer.as_ref() {
+            observer(dir, addr, len);
+        }
+    }
+}
+
+impl DeviceIo for OpenBus {
+    fn read(&self, _base: IoAddress, offset: IoAddress, data: &mut [u8]) -> DeviceIoResult<()> {
+        self.reads.fetch_add(1, AtomicOrdering::Relaxed);
+        self.observe(IoDirection::Read, offset, data.len());
+        for byte in data.iter_mut() {
+            *byte = 0xff;
+        }
+        Ok(())
+    }
+
+    fn write(&self, _base: IoAddress, offset: IoAddress, data: &[u8]) -> DeviceIoResult<()> {
+        self.writes.fetch_add(1, AtomicOrdering::Relaxed);
+        self.observe(IoDirection::Write, offset, data.len());
+        Ok(())
+    }
+}
+
+/// `IoManager` shared by all vCPU threads with read-copy-update semantics.
+///
+/// VM exits are dispatched on the currently published `IoManager` snapshot
+/// without any locking. Updates are serialized, applied to a private copy of
+/// the current snapshot and then published in one atomic step, so a vCPU
+/// either observes the `IoManager` before or after an update, never a
+/// partially updated one.
+pub struct SharedIoManager {
+    current: ArcSwap<IoManager>,
+    update_lock: Mutex<()>,
+}
+
+impl Default for SharedIoManager {
+    fn default() -> Self {
+        SharedIoManager::new(IoManager::default())
+    }
+}
+
+impl SharedIoManager {
+    /// Create a `SharedIoManager` publishing `io_mgr` as the initial snapshot.
+    pub fn new(io_mgr: IoManager) -> Self {
+        SharedIoManager {
+            current: ArcSwap::from_pointee(io_mgr),
+            update_lock: Mutex::new(()),
+        }
+    }
+
+    /// Get a cheap, lock-free handle on the current snapshot.
+    ///
+    /// The guard is meant to be short lived, e.g. held while handling one VM
+    /// exit. Use [snapshot](struct.SharedIoManager.html#method.snapshot) to
+    /// keep a snapshot around for longer.
+    pub fn load(&self) -> Guard<Arc<IoManager>> {
+        self.current.load()
+    }
+
+    /// Get a reference counted pointer on the current snapshot.
+    pub fn snapshot(&self) -> Arc<IoManager> {
+        self.current.load_full()
+    }
+
+    /// Apply `f` to a copy of the current snapshot and publish the result.
+    ///
+    /// Nothing is published if `f` fails, and the current snapshot stays
+    /// visible to the vCPU threads while `f` runs. The IO event registrations
+    /// `f` offloaded to a backend before failing are undone, on a best effort
+    /// basis: a backend failing to undo one is not reported.
+    pub fn update<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut IoManager) -> Result<T>,
+    {
+        // Publishing never happens half-way, so a panicking updater leaves a
+        // consistent snapshot behind and the poison can be ignored.
+        let _guard = self
+            .update_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let current = self.current.load_full();
+        let mut io_mgr = IoManager::clone(&current);
+        match f(&mut io_mgr) {
+            Ok(ret) => {
+                self.current.store(Arc::new(io_mgr));
+                Ok(ret)
+            }
+            Err(e) => {
+                io_mgr.rollback_ioevents(&current);
+                Err(e)
+            }
+        }
+    }
+
+    /// Register a new device IO with its allocated resources.
+    ///
+    /// See [IoManager::register_device_io](struct.IoManager.html#method.register_device_io).
+    pub fn register_device_io<R: DeviceResourcesRef>(
+        &self,
+        device: Arc<dyn DeviceIo>,
+        resources: R,
+    ) -> Result<DeviceHandle> {
+        self.update(|io_mgr| io_mgr.register_device_io(device, resources))
+    }
+
+    /// Unregister a device from the `SharedIoManager`.
+    ///
+    /// See [IoManager::unregister_device](struct.IoManager.html#method.unregister_device).
+    pub fn unregister_device(
+        &self,
+        handle: DeviceHandle,
+    ) -> Result<(Arc<dyn DeviceIo>, Vec<Resource>)> {
+        self.update(|io_mgr| io_mgr.unregister_device(handle))
+    }
+
+    /// Handle a PIO read VM exit on the current snapshot.
+    pub fn pio_read(&self, addr: u16, data: &mut [u8]) -> Result<()> {
+        self.current.load().pio_read(addr, data)
+    }
+
+    /// Handle a PIO write VM exit on the current snapshot.
+    pub fn pio_write(&self, addr: u16, data: &[u8]) -> Result<()> {
+        self.current.load().pio_write(addr, data)
+    }
+
+    /// Handle a MMIO read VM exit on the current snapshot.
+    pub fn mmio_read(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        self.current.load().mmio_read(addr, data)
+    }
+
+    /// Handle a MMIO write VM exit on the current snapshot.
+    pub fn mmio_write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.current.load().mmio_write(addr, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coalesced::{CoalescedEntry, SoftwareRing};
+    use crate::trace::{RingTracer, TraceRecord};
+    use crate::DeviceIoResult;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+
+    const PIO_ADDRESS_SIZE: u16 = 4;
+    const PIO_ADDRESS_BASE: u16 = 0x40;
+    const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
+    const MMIO_ADDRESS_BASE: u64 = 0x1234_5678;
+    const LEGACY_IRQ: u32 = 4;
+    const CONFIG_DATA: u32 = 0x1234;
+
+    struct DummyDevice {
+        config: Mutex<u32>,
+        writes: AtomicUsize,
+        id: DeviceId,
+    }
+
+    impl DummyDevice {
+        fn new(config: u32) -> Self {
+            DummyDevice {
+                config: Mutex::new(config),
+                writes: AtomicUsize::new(0),
+                id: DeviceId::new("dummy0", "dummy", 0),
+            }
+        }
+    }
+
+    impl DeviceIo for DummyDevice {
+        fn read(
+            &self,
+            _base: IoAddress,
+            _offset: IoAddress,
+            data: &mut [u8],
+        ) -> DeviceIoResult<()> {
+            if data.len() > 4 {
+                return Err(DeviceIoError::UnsupportedAccessWidth(data.len()));
+            }
+            for (idx, iter) in data.iter_mut().enumerate() {
+                let config = self.config.lock().expect("failed to acquire lock");
+                *iter = (*config >> (idx * 8) & 0xff) as u8;
+            }
+            Ok(())
+        }
+
+        fn write(&self, _base: IoAddress, _offset: IoAddress, data: &[u8]) -> DeviceIoResult<()> {
+            let mut config = self.config.lock().expect("failed to acquire lock");
+            *config = u32::from(data[0]) & 0xff;
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn id(&self) -> Option<&DeviceId> {
+            Some(&self.id)
+        }
+    }
+
+    // Device logging all its accesses, reads return the low byte of the
+    // offset of each accessed byte.
+    #[derive(Default)]
+    struct LogDevice {
+        accesses: Mutex<Vec<(IoDirection, IoAddress, IoAddress, usize)>>,
+    }
+
+    impl LogDevice {
+        fn take(&self) -> Vec<(IoDirection, IoAddress, IoAddress, usize)> {
+            std::mem::take(&mut *self.accesses.lock().unwrap())
+        }
+    }
+
+    impl DeviceIo for LogDevice {
+        fn read(&self, base: IoAddress, offset: IoAddress, data: &mut [u8]) -> DeviceIoResult<()> {
+            for (idx, byte) in data.iter_mut().enumerate() {
+                *byte = (offset.raw_value() + idx as u64) as u8;
+            }
+            self.accesses
+                .lock()
+                .unwrap()
+                .push((IoDirection::Read, base, offset, data.len()));
+            Ok(())
+        }
+
+        fn write(&self, base: IoAddress, offset: IoAddress, data: &[u8]) -> DeviceIoResult<()> {
+            self.accesses
+                .lock()
+                .unwrap()
+                .push((IoDirection::Write, base, offset, data.len()));
+            Ok(())
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_io_manager_send_sync() {
+        assert_send_sync::<Arc<dyn DeviceIo>>();
+        assert_send_sync::<IoManager>();
+        assert_send_sync::<SharedIoManager>();
+    }
+
+    #[test]
+    fn test_multi_threads_io() {
+        const THREADS: usize = 8;
+        const LOOPS: usize = 1000;
+
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let resource = [
+            Resource::PioAddressRange {
+                base: PIO_ADDRESS_BASE,
+                size: PIO_ADDRESS_SIZE,
+            },
+            Resource::MmioAddressRange {
+                base: MMIO_ADDRESS_BASE,
+                size: MMIO_ADDRESS_SIZE,
+            },
+        ];
+        assert!(io_mgr.register_device_io(dum.clone(), &resource).is_ok());
+
+        let io_mgr = Arc::new(io_mgr);
+        let threads: Vec<_> = (0..THREADS)
+            .map(|idx| {
+                let io_mgr = io_mgr.clone();
+                thread::spawn(move || {
+                    let mut data = [0; 4];
+                    for _ in 0..LOOPS {
+                        io_mgr.pio_write(PIO_ADDRESS_BASE, &[idx as u8]).unwrap();
+                        io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).unwrap();
+                        // Whatever thread wrote last, the device state is consistent.
+                        assert!((data[0] as usize) < THREADS);
+                        assert_eq!(&data[1..], &[0, 0, 0]);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(dum.writes.load(Ordering::SeqCst), THREADS * LOOPS);
+    }
+
+    #[test]
+    fn test_register_unregister_device_io() {
+        let mut io_mgr = IoManager::new();
+        let dummy = DummyDevice::new(0);
+        let dum = Arc::new(dummy);
+
+        let mut resource: Vec<Resource> = Vec::new();
+        let mmio = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        let irq = Resource::LegacyIrq(LEGACY_IRQ);
+
+        let pio = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        };
+
+        resource.push(mmio);
+        resource.push(irq);
+        resource.push(pio);
+
+        let handle = io_mgr.register_device_io(dum.clone(), &resource).unwrap();
+        let other = io_mgr
+            .register_device_io(
+                dum.clone(),
+                &[Resource::PioAddressRange {
+                    base: PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE,
+                    size: PIO_ADDRESS_SIZE,
+                }],
+            )
+            .unwrap();
+        assert_ne!(handle, other);
+
+        let (device, resources) = io_mgr.unregister_device(handle).unwrap();
+        assert!(Arc::ptr_eq(&device, &(dum.clone() as Arc<dyn DeviceIo>)));
+        assert_eq!(resources, resource);
+
+        // All the ranges of the device are gone, the other device is left.
+        let mut data = [0; 4];
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_err());
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_err());
+        assert!(io_mgr
+            .pio_read(PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE, &mut data)
+            .is_ok());
+
+        // Handles can't be used twice.
+        assert!(io_mgr.unregister_device(handle).is_err());
+
+        // The ranges can be registered again, and handles aren't reused.
+        let again = io_mgr.register_device_io(dum, &resource).unwrap();
+        assert_ne!(again, handle);
+        assert_ne!(again, other);
+    }
+
+    #[test]
+    fn test_register_overlap_device_io() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(0));
+
+        let first = Resource::MmioAddressRange {
+            base: 0x1000,
+            size: 0x1000,
+        };
+        assert!(io_mgr
+            .register_device_io(dum.clone(), std::slice::from_ref(&first))
+            .is_ok());
+
+        // Overlapping the tail of the preceding range.
+        let inner = Resource::MmioAddressRange {
+            base: 0x1800,
+            size: 0x100,
+        };
+        match io_mgr.register_device_io(dum.clone(), std::slice::from_ref(&inner)) {
+            Err(Error::DeviceOverlap {
+                range,
+                existing,
+                device,
+            }) => {
+                assert_eq!(range, inner);
+                assert_eq!(existing, first);
+                assert_eq!(device.unwrap().name, "dummy0");
+            }
+            _ => panic!("overlapping range was accepted"),
+        }
+
+        // Overlapping the head of the following range.
+        let head = Resource::MmioAddressRange {
+            base: 0x800,
+            size: 0x801,
+        };
+        assert!(io_mgr.register_device_io(dum.clone(), &[head]).is_err());
+
+        // Empty ranges are rejected.
+        let empty = Resource::MmioAddressRange {
+            base: 0x3000,
+            size: 0,
+        };
+        match io_mgr.register_device_io(dum.clone(), std::slice::from_ref(&empty)) {
+            Err(Error::InvalidRange(range)) => assert_eq!(range, empty),
+            _ => panic!("empty range was accepted"),
+        }
+        let next = Resource::MmioAddressRange {
+            base: 0x2ff0,
+            size: 0x20,
+        };
+        assert!(io_mgr
+            .register_device_io(dum.clone(), std::slice::from_ref(&next))
+            .is_ok());
+
+        // Adjacent ranges on both sides are fine.
+        let before = Resource::MmioAddressRange {
+            base: 0x800,
+            size: 0x800,
+        };
+        let after = Resource::MmioAddressRange {
+            base: 0x2000,
+            size: 0x10,
+        };
+        assert!(io_mgr
+            .register_device_io(dum.clone(), &[before, after])
+            .is_ok());
+
+        // A failing registration rolls back the ranges already inserted.
+        let pio = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        };
+        let conflict = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE + 1,
+            size: PIO_ADDRESS_SIZE,
+        };
+        assert!(io_mgr
+            .register_device_io(dum.clone(), &[pio.clone(), conflict])
+            .is_err());
+        assert!(io_mgr.register_device_io(dum.clone(), &[pio]).is_ok());
+
+        // Ranges running past the end of their bus are rejected, ranges
+        // ending at the end of their bus are fine.
+        let wrapping = [
+            Resource::MmioAddressRange {
+                base: 0xffff_ffff_ffff_f000,
+                size: 0x2000,
+            },
+            Resource::PioAddressRange {
+                base: 0xfff0,
+                size: 0x20,
+            },
+        ];
+        for res in wrapping.iter() {
+            match io_mgr.register_device_io(dum.clone(), std::slice::from_ref(res)) {
+                Err(Error::InvalidRange(range)) => assert_eq!(range, *res),
+                _ => panic!("range past the end of the bus was accepted"),
+            }
+        }
+        let last = [
+            Resource::MmioAddressRange {
+                base: 0xffff_ffff_ffff_f000,
+                size: 0x1000,
+            },
+            Resource::PioAddressRange {
+                base: 0xfff0,
+                size: 0x10,
+            },
+        ];
+        assert!(io_mgr.register_device_io(dum, &last).is_ok());
+    }
+
+    #[test]
+    fn test_mmio_read_write() {
+        let mut io_mgr: IoManager = Default::default();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mut resource: Vec<Resource> = Vec::new();
+
+        let mmio = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        resource.push(mmio);
+        assert!(io_mgr.register_device_io(dum.clone(), &resource).is_ok());
+
+        let mut data = [0; 4];
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_ok());
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+
+        assert!(io_mgr
+            .mmio_read(MMIO_ADDRESS_BASE + MMIO_ADDRESS_SIZE, &mut data)
+            .is_err());
+
+        let mut wide = [0; 8];
+        match io_mgr.mmio_read(MMIO_ADDRESS_BASE + 4, &mut wide) {
+            Err(Error::DeviceIo {
+                addr: IoAddress::Mmio(addr),
+                device: Some(id),
+                error: DeviceIoError::UnsupportedAccessWidth(8),
+            }) => {
+                assert_eq!(addr, MMIO_ADDRESS_BASE + 4);
+                assert_eq!(id, DeviceId::new("dummy0", "dummy", 0));
+            }
+            _ => panic!("device error was not propagated"),
+        }
+
+        data = [0; 4];
+        assert!(io_mgr.mmio_write(MMIO_ADDRESS_BASE, &data).is_ok());
+        assert_eq!(*dum.config.lock().unwrap(), 0);
+
+        assert!(io_mgr
+            .mmio_write(MMIO_ADDRESS_BASE + MMIO_ADDRESS_SIZE, &data)
+            .is_err());
+    }
+
+    #[test]
+    fn test_pio_read_write() {
+        let mut io_mgr: IoManager = Default::default();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mut resource: Vec<Resource> = Vec::new();
+
+        let pio = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        };
+        resource.push(pio);
+        assert!(io_mgr.register_device_io(dum.clone(), &resource).is_ok());
+
+        let mut data = [0; 4];
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+
+        assert!(io_mgr
+            .pio_read(PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE, &mut data)
+            .is_err());
+
+        data = [0; 4];
+        assert!(io_mgr.pio_write(PIO_ADDRESS_BASE, &data).is_ok());
+        assert_eq!(*dum.config.lock().unwrap(), 0);
+
+        assert!(io_mgr
+            .pio_write(PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE, &data)
+            .is_err());
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let policy = AccessPolicy::new(&[1, 2, 4, 3], true, ViolationAction::Split);
+        assert!(policy.allows(0x10, 4));
+        assert!(policy.allows(0x12, 2));
+        assert!(!policy.allows(0x12, 4));
+        assert!(!policy.allows(0x10, 8));
+        assert!(!policy.allows(0x10, 3));
+        assert_eq!(policy.split(0x10, 8), Some(vec![4, 4]));
+        assert_eq!(policy.split(0x11, 6), Some(vec![1, 2, 2, 1]));
+
+        let policy = AccessPolicy::new(&[4], false, ViolationAction::Split);
+        assert!(policy.allows(0x11, 4));
+        assert_eq!(policy.split(0x11, 8), Some(vec![4, 4]));
+        assert_eq!(policy.split(0x10, 6), None);
+
+        assert!(AccessPolicy::default().allows(0x11, 8));
+    }
+
+    #[test]
+    fn test_access_policy_enforcement() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mmio = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        let handle = io_mgr
+            .register_device_io(dum.clone(), std::slice::from_ref(&mmio))
+            .unwrap();
+
+        let policy = AccessPolicy::new(&[1, 2, 4], true, ViolationAction::Reject);
+        assert!(io_mgr.set_access_policy(handle, &mmio, policy).is_ok());
+
+        let mut data = [0; 8];
+        match io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data) {
+            Err(Error::InvalidAccess {
+                len: 8,
+                device: Some(id),
+                ..
+            }) => assert_eq!(id, DeviceId::new("dummy0", "dummy", 0)),
+            _ => panic!("invalid access was forwarded to the device"),
+        }
+        assert!(io_mgr
+            .mmio_write(MMIO_ADDRESS_BASE + 1, &data[..2])
+            .is_err());
+        assert_eq!(dum.writes.load(Ordering::SeqCst), 0);
+        assert!(io_mgr.mmio_write(MMIO_ADDRESS_BASE + 2, &[0x56, 0]).is_ok());
+        assert_eq!(dum.writes.load(Ordering::SeqCst), 1);
+
+        let policy = AccessPolicy::new(&[1, 2, 4], true, ViolationAction::Split);
+        assert!(io_mgr.set_access_policy(handle, &mmio, policy).is_ok());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_ok());
+        assert_eq!(data, [0x56, 0, 0, 0, 0x56, 0, 0, 0]);
+        assert!(io_mgr.mmio_write(MMIO_ADDRESS_BASE + 1, &data[..3]).is_ok());
+        assert_eq!(dum.writes.load(Ordering::SeqCst), 3);
+
+        let policy = AccessPolicy::new(&[1], true, ViolationAction::PassThrough);
+        assert!(io_mgr.set_access_policy(handle, &mmio, policy).is_ok());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_err());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data[..4]).is_ok());
+
+        // The policy applies to ranges owned by the device only.
+        let other = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: 0x10,
+        };
+        match io_mgr.set_access_policy(handle, &other, policy) {
+            Err(Error::RangeNotFound(range)) => assert_eq!(range, other),
+            _ => panic!("policy set on an unknown range"),
+        }
+        io_mgr.unregister_device(handle).unwrap();
+        assert!(io_mgr.set_access_policy(handle, &mmio, policy).is_err());
+    }
+
+    #[test]
+    fn test_range_crossing() {
+        let mut io_mgr = IoManager::new();
+        let first = Arc::new(LogDevice::default());
+        let second = Arc::new(LogDevice::default());
+        let second_range = Resource::MmioAddressRange {
+            base: 0x1010,
+            size: 0x10,
+        };
+        let res = [Resource::MmioAddressRange {
+            base: 0x1000,
+            size: 0x10,
+        }];
+        assert!(io_mgr.register_device_io(first.clone(), &res).is_ok());
+        let handle = io_mgr
+            .register_device_io(second.clone(), std::slice::from_ref(&second_range))
+            .unwrap();
+
+        // Crossing accesses are rejected by default.
+        let mut data = [0; 8];
+        match io_mgr.mmio_write(0x100c, &data) {
+            Err(Error::RangeCrossing {
+                addr: IoAddress::Mmio(0x100c),
+                len: 8,
+                device: None,
+            }) => {}
+            _ => panic!("crossing access was not rejected"),
+        }
+        assert!(first.take().is_empty());
+
+        io_mgr.set_crossing_policy(CrossingPolicy::Split);
+        assert!(io_mgr.mmio_read(0x100c, &mut data).is_ok());
+        assert_eq!(data, [0xc, 0xd, 0xe, 0xf, 0, 1, 2, 3]);
+        assert_eq!(
+            first.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(0x1000),
+                IoAddress::Mmio(0xc),
+                4
+            )]
+        );
+        assert_eq!(
+            second.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(0x1010),
+                IoAddress::Mmio(0),
+                4
+            )]
+        );
+
+        // Nothing is written unless all the parts can be handled.
+        assert!(io_mgr.mmio_write(0x101c, &data).is_err());
+        let policy = AccessPolicy::new(&[4], true, ViolationAction::Reject);
+        assert!(io_mgr
+            .set_access_policy(handle, &second_range, policy)
+            .is_ok());
+        assert!(io_mgr.mmio_write(0x100e, &data[..4]).is_err());
+        assert!(first.take().is_empty());
+        assert!(second.take().is_empty());
+
+        // Unclaimed parts go to the fallback device.
+        let open_bus = Arc::new(OpenBus::new());
+        io_mgr.set_mmio_fallback(Some(open_bus.clone()));
+        assert!(io_mgr.mmio_read(0xffc, &mut data).is_ok());
+        assert_eq!(data, [0xff, 0xff, 0xff, 0xff, 0, 1, 2, 3]);
+        assert_eq!(open_bus.unclaimed_reads(), 1);
+        assert!(io_mgr.mmio_write(0x101c, &data).is_ok());
+        assert_eq!(open_bus.unclaimed_writes(), 1);
+        assert_eq!(
+            second.take(),
+            vec![(
+                IoDirection::Write,
+                IoAddress::Mmio(0x1010),
+                IoAddress::Mmio(0xc),
+                4
+            )]
+        );
+    }
+
+    #[derive(Default)]
+    struct CountingNotifier {
+        count: AtomicUsize,
+    }
+
+    impl IoEventNotifier for CountingNotifier {
+        fn notify(&self) -> io::Result<()> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct DummyBackend {
+        events: Mutex<Vec<IoEvent>>,
+    }
+
+    impl IoEventBackend for DummyBackend {
+        fn register_ioevent(
+            &self,
+            event: &IoEvent,
+            _notifier: &Arc<dyn IoEventNotifier>,
+        ) -> io::Result<()> {
+            if event.datamatch == Some(0xdead) {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            self.events.lock().unwrap().push(*event);
+            Ok(())
+        }
+
+        fn unregister_ioevent(
+            &self,
+            event: &IoEvent,
+            _notifier: &Arc<dyn IoEventNotifier>,
+        ) -> io::Result<()> {
+            self.events.lock().unwrap().retain(|e| e != event);
+            Ok(())
         }
     }
 
     #[test]
-    fn test_register_unregister_device_io() {
+    fn test_bus_end() {
         let mut io_mgr = IoManager::new();
-        let dummy = DummyDevice::new(0);
-        let dum = Arc::new(dummy);
-
-        let mut resource: Vec<Resource> = Vec::new();
-        let mmio = Resource::MmioAddressRange {
-            base: MMIO_ADDRESS_BASE,
-            size: MMIO_ADDRESS_SIZE,
-        };
-        let irq = Resource::LegacyIrq(LEGACY_IRQ);
+        let open_bus = Arc::new(OpenBus::new());
+        io_mgr.set_mmio_fallback(Some(open_bus.clone()));
+        let mut data = [0; 4];
+        assert!(io_mgr.mmio_read(u64::MAX, &mut data[..1]).is_ok());
+        assert_eq!(data[0], 0xff);
 
-        resource.push(mmio);
-        resource.push(irq);
+        // Split accesses never wrap around to the start of the bus.
+        io_mgr.set_crossing_policy(CrossingPolicy::Split);
+        match io_mgr.mmio_read(0xffff_ffff_ffff_fffe, &mut data) {
+            Err(Error::RangeCrossing { len: 4, .. }) => {}
+            _ => panic!("access past the end of the bus was split"),
+        }
+        assert_eq!(open_bus.unclaimed_reads(), 1);
 
-        assert!(io_mgr.register_device_io(dum.clone(), &resource).is_ok());
-        assert!(io_mgr.unregister_device_io(&resource).is_ok())
+        let last = Arc::new(LogDevice::default());
+        let first = Arc::new(LogDevice::default());
+        let res = [
+            Resource::MmioAddressRange {
+                base: 0xffff_ffff_ffff_f000,
+                size: 0x1000,
+            },
+            Resource::PioAddressRange {
+                base: 0xfff0,
+                size: 0x10,
+            },
+        ];
+        assert!(io_mgr.register_device_io(last.clone(), &res).is_ok());
+        let res = [Resource::PioAddressRange {
+            base: 0,
+            size: 0x10,
+        }];
+        assert!(io_mgr.register_device_io(first.clone(), &res).is_ok());
+        assert!(io_mgr.mmio_read(0xffff_ffff_ffff_fffe, &mut data).is_err());
+        assert!(io_mgr.pio_read(0xfffe, &mut data).is_err());
+        assert!(io_mgr.pio_write(0xfffe, &data).is_err());
+        assert!(last.take().is_empty());
+        assert!(first.take().is_empty());
+        assert!(io_mgr.pio_read(0xfffc, &mut data).is_ok());
+        assert_eq!(last.take().len(), 1);
     }
 
     #[test]
-    fn test_mmio_read_write() {
-        let mut io_mgr: IoManager = Default::default();
+    fn test_ioevent() {
+        let mut io_mgr = IoManager::new();
         let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
-        let mut resource: Vec<Resource> = Vec::new();
-
-        let mmio = Resource::MmioAddressRange {
+        let resource = [Resource::MmioAddressRange {
             base: MMIO_ADDRESS_BASE,
             size: MMIO_ADDRESS_SIZE,
-        };
-        resource.push(mmio);
+        }];
         assert!(io_mgr.register_device_io(dum.clone(), &resource).is_ok());
 
+        let notifier = Arc::new(CountingNotifier::default());
+        let event = IoEvent::new(IoAddress::Mmio(MMIO_ADDRESS_BASE + 0x50), 4, Some(1));
+        assert!(io_mgr.register_ioevent(event, notifier.clone()).is_ok());
+        match io_mgr.register_ioevent(event, notifier.clone()) {
+            Err(Error::IoEventExists(e)) => assert_eq!(e, event),
+            _ => panic!("IO event registered twice"),
+        }
+        // Events colliding as they would with KVM are rejected too.
+        for other in [
+            IoEvent::new(IoAddress::Mmio(MMIO_ADDRESS_BASE + 0x50), 4, None),
+            IoEvent::new(IoAddress::Mmio(MMIO_ADDRESS_BASE + 0x50), 0, None),
+        ]
+        .iter()
+        {
+            match io_mgr.register_ioevent(*other, notifier.clone()) {
+                Err(Error::IoEventExists(e)) => assert_eq!(e, *other),
+                _ => panic!("colliding IO event registered"),
+            }
+        }
+        let invalid = IoEvent::new(IoAddress::Mmio(MMIO_ADDRESS_BASE), 0, Some(1));
+        assert!(io_mgr.register_ioevent(invalid, notifier.clone()).is_err());
+
+        // Matching writes only signal the notifier.
+        assert!(io_mgr
+            .mmio_write(MMIO_ADDRESS_BASE + 0x50, &[1, 0, 0, 0])
+            .is_ok());
+        assert_eq!(notifier.count.load(Ordering::SeqCst), 1);
+        assert_eq!(dum.writes.load(Ordering::SeqCst), 0);
+
+        // Others go to the device.
+        assert!(io_mgr
+            .mmio_write(MMIO_ADDRESS_BASE + 0x50, &[2, 0, 0, 0])
+            .is_ok());
+        assert!(io_mgr.pio_write(0x50, &[1, 0, 0, 0]).is_err());
+        assert_eq!(notifier.count.load(Ordering::SeqCst), 1);
+        assert_eq!(dum.writes.load(Ordering::SeqCst), 1);
+
+        // Notifications don't need a device behind the address.
+        let pio_event = IoEvent::new(IoAddress::Pio(0x50), 0, None);
+        assert!(io_mgr.register_ioevent(pio_event, notifier.clone()).is_ok());
+        assert!(io_mgr.pio_write(0x50, &[1, 0]).is_ok());
+        assert_eq!(notifier.count.load(Ordering::SeqCst), 2);
+
+        assert!(io_mgr.unregister_ioevent(&event).is_ok());
+        assert!(io_mgr.unregister_ioevent(&event).is_err());
+        assert!(io_mgr
+            .mmio_write(MMIO_ADDRESS_BASE + 0x50, &[1, 0, 0, 0])
+            .is_ok());
+        assert_eq!(notifier.count.load(Ordering::SeqCst), 2);
+        assert_eq!(dum.writes.load(Ordering::SeqCst), 2);
+
+        // Notifier failures name the device behind the address.
+        struct FailingNotifier;
+        impl IoEventNotifier for FailingNotifier {
+            fn notify(&self) -> io::Result<()> {
+                Err(io::Error::from(io::ErrorKind::WouldBlock))
+            }
+        }
+        assert!(io_mgr
+            .register_ioevent(event, Arc::new(FailingNotifier))
+            .is_ok());
+        match io_mgr.mmio_write(MMIO_ADDRESS_BASE + 0x50, &[1, 0, 0, 0]) {
+            Err(Error::IoEventNotify {
+                addr: IoAddress::Mmio(addr),
+                device: Some(id),
+                ..
+            }) => {
+                assert_eq!(addr, MMIO_ADDRESS_BASE + 0x50);
+                assert_eq!(id, DeviceId::new("dummy0", "dummy", 0));
+            }
+            _ => panic!("notifier failure was not reported"),
+        }
+    }
+
+    #[test]
+    fn test_ioevent_backend() {
+        let mut io_mgr = IoManager::new();
+        let notifier = Arc::new(CountingNotifier::default());
+        let before = IoEvent::new(IoAddress::Mmio(0x1000), 4, None);
+        assert!(io_mgr.register_ioevent(before, notifier.clone()).is_ok());
+
+        let backend = Arc::new(DummyBackend::default());
+        io_mgr.set_ioevent_backend(Some(backend.clone()));
+        let event = IoEvent::new(IoAddress::Mmio(0x1004), 4, Some(3));
+        assert!(io_mgr.register_ioevent(event, notifier.clone()).is_ok());
+        assert_eq!(*backend.events.lock().unwrap(), vec![event]);
+
+        // Backend failures abort the registration.
+        let failing = IoEvent::new(IoAddress::Mmio(0x1008), 4, Some(0xdead));
+        match io_mgr.register_ioevent(failing, notifier.clone()) {
+            Err(Error::IoEventBackend(_)) => {}
+            _ => panic!("backend error was not reported"),
+        }
+        assert!(io_mgr.unregister_ioevent(&failing).is_err());
+        assert!(!io_mgr.mmio_ioevents.contains_key(&0x1008));
+
+        // Only offloaded events are removed from the backend.
+        assert!(io_mgr.unregister_ioevent(&before).is_ok());
+        assert_eq!(backend.events.lock().unwrap().len(), 1);
+        assert!(io_mgr.unregister_ioevent(&event).is_ok());
+        assert!(backend.events.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_coalesced_mmio() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(LogDevice::default());
+        let resource = [Resource::MmioAddressRange {
+            base: 0x1000,
+            size: 0x1000,
+        }];
+        assert!(io_mgr.register_device_io(dev.clone(), &resource).is_ok());
+
+        assert!(io_mgr.register_coalesced_zone(0x1000, 0x100).is_ok());
+        match io_mgr.register_coalesced_zone(0x10f0, 0x100) {
+            Err(Error::CoalescedZoneOverlap {
+                base: 0x1000,
+                size: 0x100,
+            }) => {}
+            _ => panic!("overlapping coalesced zone was accepted"),
+        }
+        for &(base, size) in [(0x3000, 0), (u64::MAX, 2)].iter() {
+            match io_mgr.register_coalesced_zone(base, size) {
+                Err(Error::InvalidCoalescedZone { .. }) => {}
+                _ => panic!("invalid coalesced zone was accepted"),
+            }
+        }
+        assert!(io_mgr.register_coalesced_zone(0x1100, 0x100).is_ok());
+
+        let mut ring = SoftwareRing::new(8);
+        for (addr, data) in [(0x1010, &[1u8, 2][..]), (0x1008, &[3]), (0x11fc, &[4; 4])] {
+            assert!(ring.push(CoalescedEntry::new(addr, data).unwrap()));
+        }
+        assert_eq!(io_mgr.drain_coalesced(&mut ring).unwrap(), 3);
+        assert!(ring.is_empty());
+        assert_eq!(
+            dev.take(),
+            vec![
+                (
+                    IoDirection::Write,
+                    IoAddress::Mmio(0x1000),
+                    IoAddress::Mmio(0x10),
+                    2
+                ),
+                (
+                    IoDirection::Write,
+                    IoAddress::Mmio(0x1000),
+                    IoAddress::Mmio(0x8),
+                    1
+                ),
+                (
+                    IoDirection::Write,
+                    IoAddress::Mmio(0x1000),
+                    IoAddress::Mmio(0x1fc),
+                    4
+                ),
+            ]
+        );
+
+        // Draining stops at the first write outside of the coalesced zones.
+        assert!(io_mgr.unregister_coalesced_zone(0x1100, 0x10).is_err());
+        assert!(io_mgr.unregister_coalesced_zone(0x1100, 0x100).is_ok());
+        for addr in [0x1000, 0x1100, 0x1004] {
+            assert!(ring.push(CoalescedEntry::new(addr, &[0]).unwrap()));
+        }
+        match io_mgr.drain_coalesced(&mut ring) {
+            Err(Error::NotCoalesced(IoAddress::Mmio(0x1100))) => {}
+            _ => panic!("write outside of coalesced zones was replayed"),
+        }
+        assert_eq!(ring.len(), 1);
+        assert_eq!(dev.take().len(), 1);
+        assert_eq!(io_mgr.drain_coalesced(&mut ring).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_register_region() {
+        let mut io_mgr = IoManager::new();
+        let bar0 = Arc::new(LogDevice::default());
+        let bar1 = Arc::new(LogDevice::default());
+        let overlay = Arc::new(LogDevice::default());
+
+        // Host bridge window holding two BARs, partly hidden by an overlay.
+        let mut window = IoRegion::new_container(0x1000);
+        window
+            .add_subregion(0x0, 0, IoRegion::new_device(0x400, bar0.clone()))
+            .unwrap();
+        window
+            .add_subregion(0x800, 0, IoRegion::new_device(0x400, bar1.clone()))
+            .unwrap();
+        let mut root = IoRegion::new_container(0x2000);
+        root.add_subregion(0x0, 0, window).unwrap();
+        root.add_subregion(0x200, 1, IoRegion::new_device(0x800, overlay.clone()))
+            .unwrap();
+
+        let handles = io_mgr
+            .register_region(&root, IoAddress::Mmio(0x1_0000))
+            .unwrap();
+        assert_eq!(handles.len(), 3);
+
         let mut data = [0; 4];
-        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_ok());
-        assert_eq!(data, [0x34, 0x12, 0, 0]);
+        for addr in [0x1_0100, 0x1_0300, 0x1_0b00, 0x1_0c00] {
+            let _ = io_mgr.mmio_read(addr, &mut data);
+        }
+        assert_eq!(
+            bar0.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(0x1_0000),
+                IoAddress::Mmio(0x100),
+                4
+            )]
+        );
+        assert_eq!(
+            overlay.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(0x1_0200),
+                IoAddress::Mmio(0x100),
+                4
+            )]
+        );
+        // BAR1 sees offsets relative to its region start, not to the visible part.
+        assert_eq!(
+            bar1.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(0x1_0800),
+                IoAddress::Mmio(0x300),
+                4
+            )]
+        );
+        assert!(io_mgr.mmio_read(0x1_0c00, &mut data).is_err());
 
+        // Conflicting trees are not registered at all.
+        let res = [Resource::MmioAddressRange {
+            base: 0x1_0000,
+            size: 0x100,
+        }];
+        assert!(io_mgr.register_device_io(bar0.clone(), &res).is_err());
         assert!(io_mgr
-            .mmio_read(MMIO_ADDRESS_BASE + MMIO_ADDRESS_SIZE, &mut data)
+            .register_region(&root, IoAddress::Mmio(0x1_0a00))
             .is_err());
+        assert!(io_mgr.mmio_read(0x1_2100, &mut data).is_err());
 
-        data = [0; 4];
-        assert!(io_mgr.mmio_write(MMIO_ADDRESS_BASE, &data).is_ok());
-        assert_eq!(*dum.config.lock().unwrap(), 0);
+        // Each device of the tree owns its whole region.
+        let (_, resources) = io_mgr.unregister_device(handles[1]).unwrap();
+        assert_eq!(
+            resources,
+            vec![Resource::MmioAddressRange {
+                base: 0x1_0800,
+                size: 0x400
+            }]
+        );
+        assert!(io_mgr.mmio_read(0x1_0b00, &mut data).is_err());
 
+        // Unregistering a device region lets what it hid show through, with
+        // unchanged offsets.
+        let background = Arc::new(LogDevice::default());
+        let mut window = IoRegion::new_container(0x1000);
+        window
+            .add_subregion(0x0, 0, IoRegion::new_device(0x400, bar0.clone()))
+            .unwrap();
+        let mut root = IoRegion::new_container(0x2000);
+        root.add_subregion(0x0, 0, window).unwrap();
+        root.add_subregion(0x200, 1, IoRegion::new_device(0x800, overlay.clone()))
+            .unwrap();
+        root.add_subregion(0x0, -1, IoRegion::new_device(0x2000, background.clone()))
+            .unwrap();
+        let handles = io_mgr
+            .register_region(&root, IoAddress::Mmio(0x10_0000))
+            .unwrap();
+        assert!(io_mgr.unregister_device(handles[0]).is_ok());
+        for addr in [0x10_0100, 0x10_0300, 0x10_0900, 0x10_1100] {
+            assert!(io_mgr.mmio_read(addr, &mut data).is_ok());
+        }
+        assert!(overlay.take().is_empty());
+        assert_eq!(
+            bar0.take(),
+            vec![
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x10_0000),
+                    IoAddress::Mmio(0x100),
+                    4
+                ),
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x10_0000),
+                    IoAddress::Mmio(0x300),
+                    4
+                )
+            ]
+        );
+        // Including the background, through the hole of the window.
+        assert_eq!(
+            background.take(),
+            vec![
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x10_0000),
+                    IoAddress::Mmio(0x900),
+                    4
+                ),
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x10_0000),
+                    IoAddress::Mmio(0x1100),
+                    4
+                )
+            ]
+        );
+        assert!(io_mgr.unregister_device(handles[1]).is_ok());
+        assert!(io_mgr.mmio_read(0x10_0100, &mut data).is_ok());
+        assert_eq!(background.take().len(), 1);
+        assert!(io_mgr.unregister_device(handles[2]).is_ok());
+        assert!(io_mgr.mmio_read(0x10_0100, &mut data).is_err());
+        // Only the first tree is left.
+        assert_eq!(io_mgr.regions.len(), 1);
+
+        let mut pio_root = IoRegion::new_container(0x100);
+        match io_mgr.register_region(&pio_root, IoAddress::Pio(0xff80)) {
+            Err(Error::InvalidRange(_)) => {}
+            _ => panic!("region out of the PIO address space was accepted"),
+        }
+
+        // Hidden or clipped device regions must fit in the bus too.
+        pio_root
+            .add_subregion(0, 0, IoRegion::new_device(0x2_0000, bar0.clone()))
+            .unwrap();
+        match io_mgr.register_region(&pio_root, IoAddress::Pio(0)) {
+            Err(Error::InvalidRange(range)) => assert_eq!(
+                range,
+                Resource::PioAddressRange {
+                    base: 0,
+                    size: 0xffff
+                }
+            ),
+            _ => panic!("device region larger than the PIO bus was accepted"),
+        }
+        let mut mmio_root = IoRegion::new_container(0x1000);
+        mmio_root
+            .add_subregion(u64::MAX - 0x10, 0, IoRegion::new_device(0x100, bar0))
+            .unwrap();
         assert!(io_mgr
-            .mmio_write(MMIO_ADDRESS_BASE + MMIO_ADDRESS_SIZE, &data)
+            .register_region(&mmio_root, IoAddress::Mmio(0x1_0000))
             .is_err());
     }
 
     #[test]
-    fn test_pio_read_write() {
-        let mut io_mgr: IoManager = Default::default();
+    fn test_register_alias() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(LogDevice::default());
+        let res = [
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x100,
+            },
+            Resource::PioAddressRange {
+                base: 0x3b0,
+                size: 0x10,
+            },
+        ];
+        let handle = io_mgr.register_device_io(dev.clone(), &res).unwrap();
+
+        let alias = Resource::MmioAddressRange {
+            base: 0x2000,
+            size: 0x80,
+        };
+        let alias_handle = io_mgr
+            .register_alias(&alias, IoAddress::Mmio(0x1040))
+            .unwrap();
+        let pio_alias = Resource::PioAddressRange {
+            base: 0x3d0,
+            size: 0x8,
+        };
+        assert!(io_mgr
+            .register_alias(&pio_alias, IoAddress::Pio(0x3b4))
+            .is_ok());
+        // Aliases of aliases forward to the device too.
+        let nested = Resource::MmioAddressRange {
+            base: 0x3000,
+            size: 0x10,
+        };
+        assert!(io_mgr
+            .register_alias(&nested, IoAddress::Mmio(0x2010))
+            .is_ok());
+
+        let mut data = [0; 2];
+        for addr in [0x1050, 0x2010, 0x3000] {
+            assert!(io_mgr.mmio_read(addr, &mut data).is_ok());
+            assert_eq!(data, [0x50, 0x51]);
+        }
+        assert!(io_mgr.pio_write(0x3d1, &data).is_ok());
+        assert_eq!(
+            dev.take(),
+            vec![
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x1000),
+                    IoAddress::Mmio(0x50),
+                    2
+                ),
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x1000),
+                    IoAddress::Mmio(0x50),
+                    2
+                ),
+                (
+                    IoDirection::Read,
+                    IoAddress::Mmio(0x1000),
+                    IoAddress::Mmio(0x50),
+                    2
+                ),
+                (
+                    IoDirection::Write,
+                    IoAddress::Pio(0x3b0),
+                    IoAddress::Pio(0x5),
+                    2
+                ),
+            ]
+        );
+
+        // The aliased window must be within a single range of the same bus.
+        let wide = Resource::MmioAddressRange {
+            base: 0x4000,
+            size: 0x100,
+        };
+        match io_mgr.register_alias(&wide, IoAddress::Mmio(0x1040)) {
+            Err(Error::InvalidRange(range)) => assert_eq!(range, wide),
+            _ => panic!("alias larger than its target was accepted"),
+        }
+        assert!(io_mgr.register_alias(&wide, IoAddress::Pio(0x3b0)).is_err());
+        assert!(io_mgr
+            .register_alias(&wide, IoAddress::Mmio(0x5000))
+            .is_err());
+        assert!(io_mgr
+            .register_alias(&res[0], IoAddress::Mmio(0x1000))
+            .is_err());
+        for invalid in [
+            Resource::MmioAddressRange {
+                base: 0x4000,
+                size: 0,
+            },
+            Resource::MmioAddressRange {
+                base: u64::MAX,
+                size: 0x10,
+            },
+        ]
+        .iter()
+        {
+            match io_mgr.register_alias(invalid, IoAddress::Mmio(0x1000)) {
+                Err(Error::InvalidRange(range)) => assert_eq!(range, *invalid),
+                _ => panic!("invalid alias range was accepted"),
+            }
+        }
+
+        // Aliases follow the access policy of their target.
+        let policy = AccessPolicy::new(&[4], false, ViolationAction::Reject);
+        assert!(io_mgr.set_access_policy(handle, &res[0], policy).is_ok());
+        for addr in [0x1050, 0x2010, 0x3000] {
+            match io_mgr.mmio_read(addr, &mut data) {
+                Err(Error::InvalidAccess { .. }) => {}
+                _ => panic!("access policy not enforced at {:#x}", addr),
+            }
+        }
+        assert!(io_mgr.pio_read(0x3d1, &mut data).is_ok());
+        assert!(io_mgr
+            .set_access_policy(handle, &res[0], AccessPolicy::default())
+            .is_ok());
+        assert!(io_mgr.mmio_read(0x3000, &mut data).is_ok());
+        dev.take();
+
+        let (_, resources) = io_mgr.unregister_device(alias_handle).unwrap();
+        assert_eq!(resources, vec![alias]);
+        assert!(io_mgr.mmio_read(0x2010, &mut data).is_err());
+        assert!(io_mgr.mmio_read(0x3000, &mut data).is_ok());
+
+        // Aliases go away with the device.
+        assert!(io_mgr.unregister_device(handle).is_ok());
+        assert!(io_mgr.mmio_read(0x3000, &mut data).is_err());
+        assert!(io_mgr.pio_read(0x3d1, &mut data).is_err());
+    }
+
+    #[test]
+    fn test_set_enabled() {
+        let mut io_mgr = IoManager::new();
         let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
-        let mut resource: Vec<Resource> = Vec::new();
+        let res = [
+            Resource::PioAddressRange {
+                base: PIO_ADDRESS_BASE,
+                size: PIO_ADDRESS_SIZE,
+            },
+            Resource::MmioAddressRange {
+                base: MMIO_ADDRESS_BASE,
+                size: MMIO_ADDRESS_SIZE,
+            },
+        ];
+        let handle = io_mgr.register_device_io(dum.clone(), &res).unwrap();
+        let alias = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE,
+            size: PIO_ADDRESS_SIZE,
+        };
+        let alias_handle = io_mgr
+            .register_alias(&alias, IoAddress::Pio(PIO_ADDRESS_BASE))
+            .unwrap();
+        io_mgr.set_pio_fallback(Some(Arc::new(OpenBus::new())));
 
+        // Aliases are disabled along with the device.
+        assert!(io_mgr.set_enabled(handle, false).is_ok());
+        let mut data = [0; 4];
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_err());
+        for addr in [PIO_ADDRESS_BASE, PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE] {
+            assert!(io_mgr.pio_read(addr, &mut data).is_ok());
+            assert_eq!(data, [0xff; 4]);
+        }
+
+        // Disabled ranges are still registered.
+        assert!(io_mgr.register_device_io(dum.clone(), &res[1..]).is_err());
+
+        assert!(io_mgr.set_enabled(handle, true).is_ok());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_ok());
+        for addr in [PIO_ADDRESS_BASE, PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE] {
+            assert!(io_mgr.pio_read(addr, &mut data).is_ok());
+            assert_eq!(data, [0x34, 0x12, 0, 0]);
+        }
+
+        // An alias alone can be disabled through its own handle.
+        assert!(io_mgr.set_enabled(alias_handle, false).is_ok());
+        assert!(io_mgr
+            .pio_read(PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE, &mut data)
+            .is_ok());
+        assert_eq!(data, [0xff; 4]);
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+
+        io_mgr.unregister_device(handle).unwrap();
+        assert!(io_mgr.set_enabled(handle, true).is_err());
+    }
+
+    #[test]
+    fn test_relocate_range() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let log = Arc::new(LogDevice::default());
+        let bar = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        let moved = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE + 0x800,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        let other = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE + 2 * MMIO_ADDRESS_SIZE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        let handle = io_mgr
+            .register_device_io(log.clone(), std::slice::from_ref(&bar))
+            .unwrap();
+        let other_handle = io_mgr
+            .register_device_io(dum.clone(), std::slice::from_ref(&other))
+            .unwrap();
+        let policy = AccessPolicy::new(&[4], true, ViolationAction::Reject);
+        io_mgr.set_access_policy(handle, &bar, policy).unwrap();
+        let alias = Resource::MmioAddressRange {
+            base: 0xf000_0000,
+            size: 0x10,
+        };
+        io_mgr
+            .register_alias(&alias, IoAddress::Mmio(MMIO_ADDRESS_BASE))
+            .unwrap();
+
+        // Destinations must have the same size and fit in the bus.
+        let resized = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE + 0x800,
+            size: MMIO_ADDRESS_SIZE + 1,
+        };
+        let wrapping = Resource::MmioAddressRange {
+            base: 0xffff_ffff_ffff_ff00,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        for res in [resized, wrapping].iter() {
+            match io_mgr.relocate_range(handle, &bar, res) {
+                Err(Error::InvalidRange(range)) => assert_eq!(range, *res),
+                _ => panic!("invalid destination was accepted"),
+            }
+        }
+
+        // The new range may overlap with the old one.
+        assert!(io_mgr.relocate_range(handle, &bar, &moved).is_ok());
+        let mut data = [0; 4];
+        // The device sees the same base through its aliases.
+        assert!(io_mgr.mmio_read(0xf000_0004, &mut data).is_ok());
+        assert_eq!(
+            log.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(MMIO_ADDRESS_BASE + 0x800),
+                IoAddress::Mmio(4),
+                4
+            )]
+        );
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_err());
+        assert!(io_mgr
+            .mmio_read(MMIO_ADDRESS_BASE + 0x804, &mut data)
+            .is_ok());
+        assert_eq!(
+            log.take(),
+            vec![(
+                IoDirection::Read,
+                IoAddress::Mmio(MMIO_ADDRESS_BASE + 0x800),
+                IoAddress::Mmio(4),
+                4
+            )]
+        );
+        assert!(io_mgr
+            .mmio_read(MMIO_ADDRESS_BASE + 0x805, &mut data)
+            .is_err());
+
+        // Conflicting destinations leave the range where it was.
+        match io_mgr.relocate_range(handle, &moved, &other) {
+            Err(Error::DeviceOverlap {
+                range, existing, ..
+            }) => {
+                assert_eq!(range, other);
+                assert_eq!(existing, other);
+            }
+            _ => panic!("relocation over another device"),
+        }
+        assert!(io_mgr
+            .mmio_read(MMIO_ADDRESS_BASE + 0x800, &mut data)
+            .is_ok());
+        assert_eq!(log.take().len(), 1);
+
+        assert!(io_mgr.relocate_range(handle, &bar, &moved).is_err());
+        assert!(io_mgr.relocate_range(other_handle, &moved, &bar).is_err());
         let pio = Resource::PioAddressRange {
             base: PIO_ADDRESS_BASE,
             size: PIO_ADDRESS_SIZE,
         };
-        resource.push(pio);
-        assert!(io_mgr.register_device_io(dum.clone(), &resource).is_ok());
+        assert!(io_mgr.relocate_range(handle, &moved, &pio).is_err());
+
+        assert!(io_mgr.relocate_range(handle, &moved, &bar).is_ok());
+        let (_, resources) = io_mgr.unregister_device(handle).unwrap();
+        assert_eq!(resources, vec![bar]);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let pio = |base| Resource::PioAddressRange {
+            base,
+            size: PIO_ADDRESS_SIZE,
+        };
+        let first = io_mgr
+            .register_device_io(dum.clone(), &[pio(PIO_ADDRESS_BASE)])
+            .unwrap();
+        let second = io_mgr
+            .register_device_io(dum.clone(), &[pio(PIO_ADDRESS_BASE + 0x10)])
+            .unwrap();
+
+        // Failed operations are all reported and nothing is applied.
+        let mut txn = IoTransaction::new();
+        txn.unregister_device(first)
+            .register_device_io(dum.clone(), &[pio(PIO_ADDRESS_BASE)])
+            .register_device_io(dum.clone(), &[pio(PIO_ADDRESS_BASE + 0x12)])
+            .relocate_range(second, &pio(PIO_ADDRESS_BASE), &pio(0x80))
+            .unregister_device(first);
+        match io_mgr.commit(txn) {
+            Err(Error::Transaction { failures }) => {
+                let failures: Vec<_> = failures
+                    .iter()
+                    .map(|(index, e)| match e {
+                        Error::DeviceOverlap { .. } => (*index, "overlap"),
+                        Error::RangeNotFound(_) => (*index, "range"),
+                        Error::NoDevice => (*index, "device"),
+                        _ => (*index, "other"),
+                    })
+                    .collect();
+                assert_eq!(failures, vec![(2, "overlap"), (3, "range"), (4, "device")]);
+            }
+            _ => panic!("transaction with failed operations committed"),
+        }
+        let mut data = [0; 4];
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert!(io_mgr.pio_read(0x80, &mut data).is_err());
+
+        let mut txn = IoTransaction::new();
+        txn.unregister_device(first)
+            .relocate_range(second, &pio(PIO_ADDRESS_BASE + 0x10), &pio(0x80))
+            .register_device_io(dum.clone(), &[pio(PIO_ADDRESS_BASE + 0x10)])
+            .register_device_io(dum.clone(), &[pio(PIO_ADDRESS_BASE)]);
+        let handles = io_mgr.commit(txn).unwrap();
+        assert_eq!(handles.len(), 2);
+        assert!(io_mgr.unregister_device(first).is_err());
+        assert!(io_mgr.pio_read(0x80, &mut data).is_ok());
+        let (_, resources) = io_mgr.unregister_device(handles[1]).unwrap();
+        assert_eq!(resources, vec![pio(PIO_ADDRESS_BASE)]);
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let res = [
+            Resource::PioAddressRange {
+                base: PIO_ADDRESS_BASE,
+                size: PIO_ADDRESS_SIZE,
+            },
+            Resource::MmioAddressRange {
+                base: MMIO_ADDRESS_BASE,
+                size: 0x1000,
+            },
+        ];
+        let handle = io_mgr.register_device_io(dum.clone(), &res).unwrap();
+        let alias = Resource::PioAddressRange {
+            base: 0x10,
+            size: 2,
+        };
+        let alias_handle = io_mgr
+            .register_alias(&alias, IoAddress::Pio(PIO_ADDRESS_BASE + 2))
+            .unwrap();
+        io_mgr.set_enabled(handle, false).unwrap();
+        // Disabled along with the device, but enabled back alone.
+        io_mgr.set_enabled(alias_handle, true).unwrap();
+
+        let pio: Vec<_> = io_mgr
+            .pio_ranges()
+            .map(|r| (r.range, r.handle, r.alias_of, r.enabled))
+            .collect();
+        assert_eq!(
+            pio,
+            vec![
+                (alias.clone(), alias_handle, Some(handle), true),
+                (res[0].clone(), handle, None, false),
+            ]
+        );
+        assert_eq!(io_mgr.mmio_ranges().count(), 1);
 
+        let found = io_mgr.mmio_lookup(MMIO_ADDRESS_BASE + 0xfff).unwrap();
+        assert_eq!(found.range, res[1]);
+        assert!(Arc::ptr_eq(found.device, &(dum as Arc<dyn DeviceIo>)));
+        assert!(io_mgr.mmio_lookup(MMIO_ADDRESS_BASE + 0x1000).is_none());
+        assert_eq!(io_mgr.pio_lookup(0x11).unwrap().handle, alias_handle);
+        assert!(io_mgr.pio_lookup(0x12).is_none());
+
+        assert_eq!(
+            io_mgr.to_string(),
+            "pio:\n\
+             \x20 0x0010-0x0011: device 1, dummy0 (dummy #0) (alias of device 0)\n\
+             \x20 0x0040-0x0043: device 0, dummy0 (dummy #0) [disabled]\n\
+             mmio:\n\
+             \x20 0x0000000012345678-0x0000000012346677: device 0, dummy0 (dummy #0) \
+             [disabled]\n"
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let res = [
+            Resource::PioAddressRange {
+                base: PIO_ADDRESS_BASE,
+                size: PIO_ADDRESS_SIZE,
+            },
+            Resource::MmioAddressRange {
+                base: MMIO_ADDRESS_BASE,
+                size: 0x1000,
+            },
+        ];
+        let mut data = [0; 4];
+        io_mgr.register_device_io(dum.clone(), &res[..1]).unwrap();
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert!(io_mgr.pio_lookup(PIO_ADDRESS_BASE).unwrap().stats.is_none());
+        assert!(io_mgr.pio_unclaimed_stats().is_none());
+
+        // Ranges registered before and after enabling statistics are counted.
+        io_mgr.set_stats_enabled(true);
+        io_mgr.register_device_io(dum.clone(), &res[1..]).unwrap();
+        let event = IoEvent::new(IoAddress::Mmio(MMIO_ADDRESS_BASE + 0x50), 4, None);
+        let notifier = Arc::new(CountingNotifier::default());
+        io_mgr.register_ioevent(event, notifier).unwrap();
+        let io_mgr = SharedIoManager::new(io_mgr);
+
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert!(io_mgr.pio_write(PIO_ADDRESS_BASE, &data[..1]).is_ok());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data[..2]).is_ok());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data[..3]).is_ok());
+        assert!(io_mgr.mmio_write(MMIO_ADDRESS_BASE + 0x50, &data).is_ok());
+        assert!(io_mgr.pio_read(0, &mut data).is_err());
+        assert!(io_mgr.pio_write(0, &data[..2]).is_err());
+
+        let snapshot = io_mgr.snapshot();
+        let pio = snapshot
+            .pio_lookup(PIO_ADDRESS_BASE)
+            .unwrap()
+            .stats
+            .unwrap();
+        assert_eq!(
+            pio,
+            IoStats {
+                reads: 1,
+                writes: 1,
+                bytes_read: 4,
+                bytes_written: 1,
+                widths: [1, 0, 1, 0],
+                other_widths: 0,
+            }
+        );
+        let mmio = snapshot
+            .mmio_lookup(MMIO_ADDRESS_BASE)
+            .unwrap()
+            .stats
+            .unwrap();
+        assert_eq!((mmio.reads, mmio.writes), (2, 1));
+        assert_eq!((mmio.bytes_read, mmio.bytes_written), (5, 4));
+        assert_eq!((mmio.widths, mmio.other_widths), ([0, 1, 1, 0], 1));
+        let unclaimed = snapshot.pio_unclaimed_stats().unwrap();
+        assert_eq!((unclaimed.reads, unclaimed.writes), (1, 1));
+        assert_eq!(snapshot.mmio_unclaimed_stats().unwrap().accesses(), 0);
+
+        // Fallback devices count unclaimed accesses too.
+        io_mgr
+            .update(|io_mgr| {
+                io_mgr.set_pio_fallback(Some(Arc::new(OpenBus::new())));
+                Ok(())
+            })
+            .unwrap();
+        assert!(io_mgr.pio_read(0, &mut data).is_ok());
+        assert_eq!(io_mgr.load().pio_unclaimed_stats().unwrap().reads, 2);
+
+        io_mgr
+            .update(|io_mgr| {
+                io_mgr.set_stats_enabled(false);
+                Ok(())
+            })
+            .unwrap();
+        assert!(io_mgr.load().mmio_ranges().all(|r| r.stats.is_none()));
+        assert!(io_mgr.load().pio_unclaimed_stats().is_none());
+    }
+
+    #[test]
+    fn test_tracer() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let res = [Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        }];
+        let handle = io_mgr.register_device_io(dum.clone(), &res).unwrap();
+        let tracer = Arc::new(RingTracer::new(8));
+        io_mgr.set_tracer(Some(tracer.clone()));
+
+        let mut data = [0; 2];
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert!(io_mgr.pio_write(PIO_ADDRESS_BASE, &[0x42]).is_ok());
+        assert!(io_mgr.pio_read(0, &mut data).is_err());
+        assert_eq!(
+            tracer.take(),
+            vec![
+                TraceRecord {
+                    dir: IoDirection::Read,
+                    addr: IoAddress::Pio(PIO_ADDRESS_BASE),
+                    data: vec![0x34, 0x12],
+                    handle: Some(handle),
+                    ok: true,
+                },
+                TraceRecord {
+                    dir: IoDirection::Write,
+                    addr: IoAddress::Pio(PIO_ADDRESS_BASE),
+                    data: vec![0x42],
+                    handle: Some(handle),
+                    ok: true,
+                },
+                TraceRecord {
+                    dir: IoDirection::Read,
+                    addr: IoAddress::Pio(0),
+                    data: vec![0x34, 0x12],
+                    handle: None,
+                    ok: false,
+                },
+            ]
+        );
+
+        io_mgr.set_tracer(None);
+        assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
+        assert!(tracer.records().is_empty());
+    }
+
+    #[test]
+    fn test_fallback_device() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let resource = [Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        }];
+        assert!(io_mgr.register_device_io(dum, &resource).is_ok());
+
+        let unclaimed = Arc::new(Mutex::new(Vec::new()));
+        let log = unclaimed.clone();
+        let open_bus = Arc::new(OpenBus::with_observer(move |dir, addr, len| {
+            log.lock().unwrap().push((dir, addr, len));
+        }));
+        io_mgr.set_pio_fallback(Some(open_bus.clone()));
+
+        // Claimed accesses still reach the registered device.
         let mut data = [0; 4];
         assert!(io_mgr.pio_read(PIO_ADDRESS_BASE, &mut data).is_ok());
         assert_eq!(data, [0x34, 0x12, 0, 0]);
 
-        assert!(io_mgr
-            .pio_read(PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE, &mut data)
-            .is_err());
+        let port = PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE;
+        assert!(io_mgr.pio_read(port, &mut data).is_ok());
+        assert_eq!(data, [0xff; 4]);
+        assert!(io_mgr.pio_write(port, &[0x1]).is_ok());
+        assert_eq!(open_bus.unclaimed_reads(), 1);
+        assert_eq!(open_bus.unclaimed_writes(), 1);
+        assert_eq!(
+            *unclaimed.lock().unwrap(),
+            vec![
+                (IoDirection::Read, IoAddress::Pio(port), 4),
+                (IoDirection::Write, IoAddress::Pio(port), 1),
+            ]
+        );
 
+        // Fallback devices are per bus.
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_err());
+        io_mgr.set_mmio_fallback(Some(Arc::new(OpenBus::new())));
         data = [0; 4];
-        assert!(io_mgr.pio_write(PIO_ADDRESS_BASE, &data).is_ok());
+        assert!(io_mgr.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_ok());
+        assert_eq!(data, [0xff; 4]);
+
+        io_mgr.set_pio_fallback(None);
+        assert!(io_mgr.pio_read(port, &mut data).is_err());
+    }
+
+    #[test]
+    fn test_shared_io_manager() {
+        let shared = SharedIoManager::default();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mmio = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+
+        let old = shared.snapshot();
+        let handle = shared
+            .register_device_io(dum.clone(), std::slice::from_ref(&mmio))
+            .unwrap();
+
+        // Snapshots taken before an update are left untouched.
+        let mut data = [0; 4];
+        assert!(old.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_err());
+        assert!(shared.mmio_read(MMIO_ADDRESS_BASE, &mut data).is_ok());
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+
+        // A failed update publishes nothing.
+        let pio = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        };
+        assert!(shared
+            .register_device_io(dum.clone(), &[pio, mmio.clone()])
+            .is_err());
+        assert!(shared.pio_read(PIO_ADDRESS_BASE, &mut data).is_err());
+
+        assert!(shared.mmio_write(MMIO_ADDRESS_BASE, &[0; 4]).is_ok());
         assert_eq!(*dum.config.lock().unwrap(), 0);
 
-        assert!(io_mgr
-            .pio_write(PIO_ADDRESS_BASE + PIO_ADDRESS_SIZE, &data)
+        assert!(shared.unregister_device(handle).is_ok());
+        assert!(shared.mmio_write(MMIO_ADDRESS_BASE, &[0; 4]).is_err());
+        assert!(shared
+            .load()
+            .mmio_read(MMIO_ADDRESS_BASE, &mut data)
             .is_err());
+
+        // IO events offloaded by a failed update are taken back from the
+        // backend, and the ones it unregistered are offloaded again.
+        let backend = Arc::new(DummyBackend::default());
+        let notifier = Arc::new(CountingNotifier::default());
+        let kept = IoEvent::new(IoAddress::Mmio(0x1000), 4, None);
+        let added = IoEvent::new(IoAddress::Pio(0x1000), 2, None);
+        assert!(shared
+            .update(|io_mgr| {
+                io_mgr.set_ioevent_backend(Some(backend.clone()));
+                io_mgr.register_ioevent(kept, notifier.clone())
+            })
+            .is_ok());
+        let ret: Result<()> = shared.update(|io_mgr| {
+            io_mgr.unregister_ioevent(&kept)?;
+            io_mgr.register_ioevent(added, notifier.clone())?;
+            Err(Error::NoDevice)
+        });
+        assert!(ret.is_err());
+        assert_eq!(*backend.events.lock().unwrap(), vec![kept]);
+        assert!(shared.load().mmio_ioevents.contains_key(&0x1000));
+        assert!(shared.load().pio_ioevents.is_empty());
     }
 }
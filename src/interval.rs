@@ -0,0 +1,485 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Allocation of ranges of addresses or numbers.
+//!
+//! [IntervalAllocator](struct.IntervalAllocator.html) hands out ranges of
+//! values from an inclusive interval, e.g. the PIO or MMIO address space of
+//! a VM, honouring alignment and allocation windows.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result;
+
+/// Integer type of the values managed by an `IntervalAllocator`.
+pub trait Address: Copy + Ord + fmt::Debug {
+    /// Largest value of the type.
+    const MAX: Self;
+
+    /// Convert the value to a `u64`.
+    fn to_u64(self) -> u64;
+
+    /// Convert a `u64` no larger than `MAX` to a value of the type.
+    fn from_u64(value: u64) -> Self;
+}
+
+impl Address for u16 {
+    const MAX: Self = u16::MAX;
+
+    fn to_u64(self) -> u64 {
+        u64::from(self)
+    }
+
+    fn from_u64(value: u64) -> Self {
+        value as u16
+    }
+}
+
+impl Address for u32 {
+    const MAX: Self = u32::MAX;
+
+    fn to_u64(self) -> u64 {
+        u64::from(self)
+    }
+
+    fn from_u64(value: u64) -> Self {
+        value as u32
+    }
+}
+
+impl Address for u64 {
+    const MAX: Self = u64::MAX;
+
+    fn to_u64(self) -> u64 {
+        self
+    }
+
+    fn from_u64(value: u64) -> Self {
+        value
+    }
+}
+
+/// Where an `IntervalAllocator` places new allocations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum AllocPolicy {
+    /// At the lowest possible address.
+    #[default]
+    FirstFit,
+    /// In the smallest free range it fits in, limiting fragmentation.
+    BestFit,
+    /// At the highest possible address.
+    TopDown,
+}
+
+/// Error type for `IntervalAllocator` usage.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The range is empty or doesn't fit in the address type.
+    InvalidRange {
+        /// First value of the range.
+        base: u64,
+        /// Number of values in the range.
+        size: u64,
+    },
+    /// The alignment isn't a power of two.
+    InvalidAlignment(u64),
+    /// The allocation window is empty.
+    InvalidWindow {
+        /// Lowest value of the window.
+        min: u64,
+        /// Highest value of the window.
+        max: u64,
+    },
+    /// No free range satisfies the allocation.
+    Exhausted,
+    /// Part of the reserved range is allocated or out of the allocator.
+    Unavailable {
+        /// First value of the range.
+        base: u64,
+        /// Number of values in the range.
+        size: u64,
+    },
+    /// Part of the freed range isn't allocated.
+    NotAllocated {
+        /// First value of the range.
+        base: u64,
+        /// Number of values in the range.
+        size: u64,
+    },
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Allocator of ranges of values from an inclusive interval.
+///
+/// Free values are kept as a set of disjoint ranges, so allocating, reserving
+/// and freeing are logarithmic in the number of free ranges, except for best
+/// fit allocations which scan all of them.
+#[derive(Clone, Debug)]
+pub struct IntervalAllocator<T: Address> {
+    first: T,
+    last: T,
+    policy: AllocPolicy,
+    // Free ranges, as inclusive bounds indexed by their first value.
+    free: BTreeMap<u64, u64>,
+}
+
+impl<T: Address> IntervalAllocator<T> {
+    /// Create an allocator of the values in [`first`, `last`], all free.
+    pub fn new(first: T, last: T) -> Self {
+        let mut free = BTreeMap::new();
+        if first <= last {
+            free.insert(first.to_u64(), last.to_u64());
+        }
+        IntervalAllocator {
+            first,
+            last,
+            policy: AllocPolicy::default(),
+            free,
+        }
+    }
+
+    /// Set where new allocations are placed, first fit by default.
+    pub fn set_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+    }
+
+    /// Allocate `size` values, the first one aligned on `align`.
+    ///
+    /// When a `window` [`min`, `max`] is given, all the allocated values lie
+    /// within it.
+    ///
+    /// Return the first allocated value.
+    pub fn allocate(&mut self, size: T, align: T, window: Option<(T, T)>) -> Result<T> {
+        let (size, align) = (size.to_u64(), align.to_u64());
+        if size == 0 {
+            return Err(Error::InvalidRange { base: 0, size });
+        }
+        if !align.is_power_of_two() {
+            return Err(Error::InvalidAlignment(align));
+        }
+        let (min, max) = match window {
+            Some((min, max)) if min > max => {
+                return Err(Error::InvalidWindow {
+                    min: min.to_u64(),
+                    max: max.to_u64(),
+                })
+            }
+            Some((min, max)) => (min.to_u64(), max.to_u64()),
+            None => (0, u64::MAX),
+        };
+
+        // Lowest and highest fitting bases in the free range [start, end].
+        let lowest = |(&start, &end): (&u64, &u64)| {
+            let base = start.max(min).checked_add(align - 1)? & !(align - 1);
+            let last = base.checked_add(size - 1)?;
+            if last <= end.min(max) {
+                Some(base)
+            } else {
+                None
+            }
+        };
+        let highest = |(&start, &end): (&u64, &u64)| {
+            let base = end.min(max).checked_sub(size - 1)? & !(align - 1);
+            if base >= start.max(min) {
+                Some(base)
+            } else {
+                None
+            }
+        };
+        let base = match self.policy {
+            AllocPolicy::FirstFit => self.free.iter().filter_map(lowest).next(),
+            AllocPolicy::TopDown => self.free.iter().rev().filter_map(highest).next(),
+            AllocPolicy::BestFit => self
+                .free
+                .iter()
+                .filter_map(|range| lowest(range).map(|base| (range.1 - range.0, base)))
+                .min()
+                .map(|(_, base)| base),
+        }
+        .ok_or(Error::Exhausted)?;
+
+        self.take(base, base + (size - 1));
+        Ok(T::from_u64(base))
+    }
+
+    /// Allocate the `size` values starting at `base`, e.g. for resources at
+    /// fixed addresses.
+    pub fn reserve(&mut self, base: T, size: T) -> Result<()> {
+        let last = self.checked_last(base, size)?;
+        let base = base.to_u64();
+        match self.free.range(..=base).next_back() {
+            Some((_, &end)) if end >= last => {
+                self.take(base, last);
+                Ok(())
+            }
+            _ => Err(Error::Unavailable {
+                base,
+                size: size.to_u64(),
+            }),
+        }
+    }
+
+    /// Free the `size` values starting at `base`.
+    ///
+    /// Any allocated values can be freed, not only whole allocations.
+    pub fn free(&mut self, base: T, size: T) -> Result<()> {
+        let mut last = self.checked_last(base, size)?;
+        let base = base.to_u64();
+        let not_allocated = Error::NotAllocated {
+            base,
+            size: size.to_u64(),
+        };
+        if base < self.first.to_u64() || last > self.last.to_u64() {
+            return Err(not_allocated);
+        }
+        if let Some((_, &end)) = self.free.range(..=last).next_back() {
+            if end >= base {
+                return Err(not_allocated);
+            }
+        }
+
+        // Merge with the adjacent free ranges.
+        let mut first = base;
+        if let Some((&start, &end)) = self.free.range(..base).next_back() {
+            if end + 1 == base {
+                self.free.remove(&start);
+                first = start;
+            }
+        }
+        if let Some(next) = last.checked_add(1) {
+            if let Some(end) = self.free.remove(&next) {
+                last = end;
+            }
+        }
+        self.free.insert(first, last);
+        Ok(())
+    }
+
+    /// Check whether all the `size` values starting at `base` are free.
+    pub fn is_free(&self, base: T, size: T) -> bool {
+        match self.checked_last(base, size) {
+            Ok(last) => match self.free.range(..=base.to_u64()).next_back() {
+                Some((_, &end)) => end >= last,
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    // Return the last value of the range of `size` values starting at `base`.
+    fn checked_last(&self, base: T, size: T) -> Result<u64> {
+        let (base, size) = (base.to_u64(), size.to_u64());
+        match base.checked_add(size.wrapping_sub(1)) {
+            Some(last) if size != 0 && last <= T::MAX.to_u64() => Ok(last),
+            _ => Err(Error::InvalidRange { base, size }),
+        }
+    }
+
+    // Remove [`base`, `last`] from the free range containing it.
+    fn take(&mut self, base: u64, last: u64) {
+        let (start, end) = match self.free.range(..=base).next_back() {
+            Some((&start, &end)) => (start, end),
+            None => return,
+        };
+        self.free.remove(&start);
+        if start < base {
+            self.free.insert(start, base - 1);
+        }
+        if last < end {
+            self.free.insert(last + 1, end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_allocate() {
+        let mut allocator = IntervalAllocator::new(0x1000u16, 0x1fff);
+        assert_eq!(allocator.allocate(0x100, 0x100, None), Ok(0x1000));
+        assert_eq!(
+            allocator.allocate(0x10, 0x1000, None),
+            Err(Error::Exhausted)
+        );
+        assert_eq!(
+            allocator.allocate(0x8, 0x1, Some((0x1ff0, 0x2000))),
+            Ok(0x1ff0)
+        );
+        assert_eq!(
+            allocator.allocate(0x8, 0x10, Some((0x1ff0, 0x2000))),
+            Err(Error::Exhausted)
+        );
+        assert_eq!(
+            allocator.allocate(0, 1, None),
+            Err(Error::InvalidRange { base: 0, size: 0 })
+        );
+        assert_eq!(
+            allocator.allocate(1, 3, None),
+            Err(Error::InvalidAlignment(3))
+        );
+        assert_eq!(
+            allocator.allocate(1, 1, Some((2, 1))),
+            Err(Error::InvalidWindow { min: 2, max: 1 })
+        );
+
+        allocator.set_policy(AllocPolicy::TopDown);
+        assert_eq!(allocator.allocate(0x10, 0x100, None), Ok(0x1f00));
+        assert_eq!(allocator.allocate(0x8, 0x1, None), Ok(0x1ff8));
+        assert_eq!(
+            allocator.allocate(0x10, 0x10, Some((0, 0x10ff))),
+            Err(Error::Exhausted)
+        );
+
+        let mut allocator = IntervalAllocator::new(0u64, u64::MAX);
+        allocator.set_policy(AllocPolicy::TopDown);
+        assert_eq!(
+            allocator.allocate(0x1000, 0x1000, None),
+            Ok(u64::MAX - 0xfff)
+        );
+        allocator.set_policy(AllocPolicy::FirstFit);
+        assert_eq!(allocator.allocate(u64::MAX - 0x1000, 1, None), Ok(0));
+        assert_eq!(allocator.allocate(1, 1, None), Ok(u64::MAX - 0x1000));
+        assert_eq!(allocator.allocate(1, 1, None), Err(Error::Exhausted));
+        assert!(allocator.free(0, u64::MAX).is_ok());
+        assert!(allocator.is_free(0, u64::MAX));
+        assert!(!allocator.is_free(u64::MAX, 1));
+    }
+
+    #[test]
+    fn test_best_fit() {
+        let mut allocator = IntervalAllocator::new(0u32, 99);
+        allocator.set_policy(AllocPolicy::BestFit);
+        for (base, size) in [(0, 10), (20, 14), (40, 10)].iter() {
+            allocator.reserve(*base, *size).unwrap();
+        }
+        // Free ranges are 10-19, 34-39 and 50-99.
+        assert_eq!(allocator.allocate(4, 1, None), Ok(34));
+        assert_eq!(allocator.allocate(4, 1, None), Ok(10));
+        assert_eq!(allocator.allocate(4, 4, None), Ok(16));
+        assert_eq!(allocator.allocate(8, 1, None), Ok(50));
+    }
+
+    #[test]
+    fn test_reserve_free() {
+        let mut allocator = IntervalAllocator::new(10u32, 19);
+        assert!(allocator.reserve(12, 2).is_ok());
+        assert_eq!(
+            allocator.reserve(13, 2),
+            Err(Error::Unavailable { base: 13, size: 2 })
+        );
+        assert!(allocator.reserve(8, 3).is_err());
+        assert!(allocator.reserve(18, 3).is_err());
+        assert!(allocator.reserve(u32::MAX, 2).is_err());
+        assert!(!allocator.is_free(12, 1));
+        assert!(allocator.is_free(14, 6));
+
+        assert_eq!(
+            allocator.free(11, 2),
+            Err(Error::NotAllocated { base: 11, size: 2 })
+        );
+        assert!(allocator.free(8, 1).is_err());
+        assert!(allocator.free(13, 1).is_ok());
+        assert!(allocator.free(12, 1).is_ok());
+        assert!(allocator.is_free(10, 10));
+        assert_eq!(allocator.allocate(10, 1, None), Ok(10));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Allocate {
+            size: u16,
+            align_shift: u32,
+            window: Option<(u16, u16)>,
+            policy: AllocPolicy,
+        },
+        Reserve {
+            base: u16,
+            size: u16,
+        },
+        Free {
+            idx: usize,
+        },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let policy = prop_oneof![
+            Just(AllocPolicy::FirstFit),
+            Just(AllocPolicy::BestFit),
+            Just(AllocPolicy::TopDown),
+        ];
+        prop_oneof![
+            (
+                1u16..0x400,
+                0u32..12,
+                proptest::option::of((any::<u16>(), any::<u16>())),
+                policy
+            )
+                .prop_map(|(size, align_shift, window, policy)| Op::Allocate {
+                    size,
+                    align_shift,
+                    window,
+                    policy,
+                }),
+            (any::<u16>(), 1u16..0x400).prop_map(|(base, size)| Op::Reserve { base, size }),
+            any::<usize>().prop_map(|idx| Op::Free { idx }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_no_overlap(ops in proptest::collection::vec(op(), 1..200)) {
+            let mut allocator = IntervalAllocator::new(0x100u16, 0xefff);
+            let mut live: Vec<(u64, u64)> = Vec::new();
+            for op in ops {
+                let range = match op {
+                    Op::Allocate { size, align_shift, window, policy } => {
+                        allocator.set_policy(policy);
+                        let align = 1u16 << align_shift;
+                        match allocator.allocate(size, align, window) {
+                            Ok(base) => {
+                                prop_assert_eq!(base % align, 0);
+                                if let Some((min, max)) = window {
+                                    prop_assert!(base >= min);
+                                    prop_assert!(u64::from(base) + u64::from(size) - 1 <= u64::from(max));
+                                }
+                                Some((u64::from(base), u64::from(size)))
+                            }
+                            Err(_) => None,
+                        }
+                    }
+                    Op::Reserve { base, size } => match allocator.reserve(base, size) {
+                        Ok(()) => Some((u64::from(base), u64::from(size))),
+                        Err(_) => None,
+                    },
+                    Op::Free { idx } => {
+                        if !live.is_empty() {
+                            let (base, size) = live.swap_remove(idx % live.len());
+                            prop_assert!(allocator.free(base as u16, size as u16).is_ok());
+                            prop_assert!(allocator.free(base as u16, size as u16).is_err());
+                        }
+                        None
+                    }
+                };
+                if let Some((base, size)) = range {
+                    prop_assert!(base >= 0x100 && base + size - 1 <= 0xefff);
+                    for &(other, other_size) in live.iter() {
+                        prop_assert!(base + size <= other || other + other_size <= base);
+                    }
+                    live.push((base, size));
+                }
+            }
+
+            // Everything is free again once all allocations are.
+            for (base, size) in live {
+                prop_assert!(allocator.free(base as u16, size as u16).is_ok());
+            }
+            prop_assert!(allocator.is_free(0x100, 0xef00));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Write notifications bypassing device dispatch, a la KVM ioeventfd.
+//!
+//! An [IoEvent](struct.IoEvent.html) describes guest writes of interest, e.g.
+//! virtio queue notifications. Once registered to the
+//! [IoManager](../device_manager/struct.IoManager.html) with an
+//! [IoEventNotifier](trait.IoEventNotifier.html), matching writes only signal
+//! the notifier and never reach the device. An
+//! [IoEventBackend](trait.IoEventBackend.html), e.g. backed by KVM, can
+//! offload the same registrations so that matching writes don't even exit.
+
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use crate::IoAddress;
+
+/// Guest writes triggering a notification.
+///
+/// Follows KVM ioeventfd semantics: a write matches if it starts at `addr`,
+/// is `len` bytes wide and, if `datamatch` is set, writes this value in
+/// little-endian order. A zero `len` matches writes of any width, and then
+/// can't be combined with `datamatch`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IoEvent {
+    /// Guest address of the write.
+    pub addr: IoAddress,
+    /// Width of the write in bytes, among 0, 1, 2, 4 and 8.
+    pub len: usize,
+    /// Value the write must carry, if any.
+    pub datamatch: Option<u64>,
+}
+
+impl IoEvent {
+    /// Create a new IO event.
+    pub fn new(addr: IoAddress, len: usize, datamatch: Option<u64>) -> Self {
+        IoEvent {
+            addr,
+            len,
+            datamatch,
+        }
+    }
+
+    /// Check whether the event description is valid.
+    pub fn is_valid(&self) -> bool {
+        match self.len {
+            0 => self.datamatch.is_none(),
+            1 | 2 | 4 | 8 => true,
+            _ => false,
+        }
+    }
+
+    /// Check whether the event conflicts with `other`, following the rule of
+    /// KVM_IOEVENTFD: events at the same address conflict unless both have a
+    /// width and either their widths or their values differ.
+    pub fn collides(&self, other: &IoEvent) -> bool {
+        if self.addr != other.addr || self.addr.is_pio() != other.addr.is_pio() {
+            return false;
+        }
+        self.len == 0
+            || other.len == 0
+            || (self.len == other.len
+                && (self.datamatch.is_none()
+                    || other.datamatch.is_none()
+                    || self.datamatch == other.datamatch))
+    }
+
+    /// Check whether writing `data` at `addr` matches the event.
+    pub fn matches(&self, addr: IoAddress, data: &[u8]) -> bool {
+        if addr != self.addr || addr.is_pio() != self.addr.is_pio() {
+            return false;
+        }
+        if self.len == 0 {
+            return true;
+        }
+        if data.len() != self.len {
+            return false;
+        }
+        self.datamatch.is_none_or(|value| {
+            let mut bytes = [0u8; 8];
+            bytes[..data.len()].copy_from_slice(data);
+            u64::from_le_bytes(bytes) == value
+        })
+    }
+}
+
+/// Notifier signaled on matching IO event writes, e.g. an eventfd.
+pub trait IoEventNotifier: Send + Sync {
+    /// Signal the notifier.
+    fn notify(&self) -> io::Result<()>;
+
+    /// File descriptor the notifier can be signaled through, if any.
+    ///
+    /// This allows an `IoEventBackend` to offload the notification, e.g. to
+    /// KVM when the notifier is an eventfd.
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Backend offloading IO event registrations, e.g. with KVM_IOEVENTFD.
+pub trait IoEventBackend: Send + Sync {
+    /// Offload a new IO event registration.
+    fn register_ioevent(
+        &self,
+        event: &IoEvent,
+        notifier: &Arc<dyn IoEventNotifier>,
+    ) -> io::Result<()>;
+
+    /// Remove an offloaded IO event registration.
+    fn unregister_ioevent(
+        &self,
+        event: &IoEvent,
+        notifier: &Arc<dyn IoEventNotifier>,
+    ) -> io::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioevent_matches() {
+        let event = IoEvent::new(IoAddress::Mmio(0x1000), 4, Some(0x1234));
+        assert!(event.is_valid());
+        assert!(event.matches(IoAddress::Mmio(0x1000), &[0x34, 0x12, 0, 0]));
+        assert!(!event.matches(IoAddress::Mmio(0x1000), &[0x35, 0x12, 0, 0]));
+        assert!(!event.matches(IoAddress::Mmio(0x1000), &[0x34, 0x12]));
+        assert!(!event.matches(IoAddress::Mmio(0x1004), &[0x34, 0x12, 0, 0]));
+        assert!(!event.matches(IoAddress::Pio(0x1000), &[0x34, 0x12, 0, 0]));
+
+        let event = IoEvent::new(IoAddress::Pio(0x60), 2, None);
+        assert!(event.matches(IoAddress::Pio(0x60), &[0xff, 0xff]));
+        assert!(!event.matches(IoAddress::Pio(0x60), &[0xff]));
+
+        let event = IoEvent::new(IoAddress::Pio(0x60), 0, None);
+        assert!(event.matches(IoAddress::Pio(0x60), &[0xff]));
+        assert!(event.matches(IoAddress::Pio(0x60), &[0; 8]));
+
+        assert!(!IoEvent::new(IoAddress::Pio(0x60), 0, Some(1)).is_valid());
+    }
+
+    #[test]
+    fn test_ioevent_collides() {
+        let event = IoEvent::new(IoAddress::Mmio(0x1000), 4, Some(1));
+        for (other, collides) in [
+            (IoEvent::new(IoAddress::Mmio(0x1000), 4, Some(1)), true),
+            (IoEvent::new(IoAddress::Mmio(0x1000), 4, None), true),
+            (IoEvent::new(IoAddress::Mmio(0x1000), 0, None), true),
+            (IoEvent::new(IoAddress::Mmio(0x1000), 4, Some(2)), false),
+            (IoEvent::new(IoAddress::Mmio(0x1000), 2, None), false),
+            (IoEvent::new(IoAddress::Mmio(0x1004), 0, None), false),
+            (IoEvent::new(IoAddress::Pio(0x1000), 4, Some(1)), false),
+        ]
+        .iter()
+        {
+            assert_eq!(event.collides(other), *collides);
+            assert_eq!(other.collides(&event), *collides);
+        }
+        assert!(!IoEvent::new(IoAddress::Pio(0x60), 3, None).is_valid());
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Allocation of interrupt numbers.
+//!
+//! [IrqAllocator](struct.IrqAllocator.html) hands out legacy IRQ lines and
+//! blocks of MSI vectors, and takes them back when devices are hot-unplugged.
+
+use std::collections::BTreeMap;
+use std::result;
+
+use crate::interval::{self, IntervalAllocator};
+use crate::resources::{MsiIrqType, Resource};
+
+// Largest number of vectors of a PCI MSI capability.
+const MAX_PCI_MSI_VECTORS: u32 = 32;
+
+// Largest number of vectors of a PCI MSI-X capability.
+const MAX_PCI_MSIX_VECTORS: u32 = 2048;
+
+/// Error type for `IrqAllocator` usage.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The number of vectors isn't supported by this type of MSI: zero, too
+    /// many, or not a power of two for PCI MSI.
+    InvalidCount {
+        /// Type of the requested vectors.
+        ty: MsiIrqType,
+        /// Number of requested vectors.
+        count: u32,
+    },
+    /// The legacy IRQ line is already allocated or not part of the pool.
+    LegacyUnavailable(u32),
+    /// All the legacy IRQ lines are allocated.
+    LegacyExhausted,
+    /// No block of free MSI vectors is large enough.
+    MsiExhausted {
+        /// Type of the requested vectors.
+        ty: MsiIrqType,
+        /// Number of requested vectors.
+        count: u32,
+    },
+    /// The freed interrupts weren't allocated, or not as a single block of
+    /// this type.
+    NotAllocated(Resource),
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Allocator of legacy IRQ lines and MSI vectors.
+#[derive(Clone, Debug)]
+pub struct IrqAllocator {
+    legacy: IntervalAllocator<u32>,
+    msi: IntervalAllocator<u32>,
+    // Allocated MSI blocks, as their type and size indexed by their base.
+    msi_blocks: BTreeMap<u32, (MsiIrqType, u32)>,
+}
+
+impl IrqAllocator {
+    /// Create an allocator of the legacy IRQ lines and MSI vectors in the
+    /// inclusive ranges `legacy` and `msi`.
+    pub fn new(legacy: (u32, u32), msi: (u32, u32)) -> Self {
+        IrqAllocator {
+            legacy: IntervalAllocator::new(legacy.0, legacy.1),
+            msi: IntervalAllocator::new(msi.0, msi.1),
+            msi_blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Allocate the legacy IRQ line `irq`, e.g. one wired to a fixed platform
+    /// device.
+    pub fn reserve_legacy(&mut self, irq: u32) -> Result<()> {
+        self.legacy
+            .reserve(irq, 1)
+            .map_err(|_| Error::LegacyUnavailable(irq))
+    }
+
+    /// Allocate the lowest free legacy IRQ line.
+    pub fn allocate_legacy(&mut self) -> Result<u32> {
+        self.legacy
+            .allocate(1, 1, None)
+            .map_err(|_| Error::LegacyExhausted)
+    }
+
+    /// Allocate a block of `count` contiguous MSI vectors of type `ty`.
+    ///
+    /// PCI MSI blocks are naturally aligned, as the device sets the vector
+    /// index in the low bits of the message data.
+    ///
+    /// Return the first vector of the block.
+    pub fn allocate_msi(&mut self, ty: MsiIrqType, count: u32) -> Result<u32> {
+        let (max, align) = match ty {
+            MsiIrqType::PciMsi if count.is_power_of_two() => (MAX_PCI_MSI_VECTORS, count),
+            MsiIrqType::PciMsi => return Err(Error::InvalidCount { ty, count }),
+            MsiIrqType::PciMsix => (MAX_PCI_MSIX_VECTORS, 1),
+            MsiIrqType::GenericMsi => (u32::MAX, 1),
+        };
+        if count == 0 || count > max {
+            return Err(Error::InvalidCount { ty, count });
+        }
+        let base = self.msi.allocate(count, align, None).map_err(|e| match e {
+            interval::Error::Exhausted => Error::MsiExhausted { ty, count },
+            _ => Error::InvalidCount { ty, count },
+        })?;
+        self.msi_blocks.insert(base, (ty, count));
+        Ok(base)
+    }
+
+    /// Free the interrupts of `res`, a legacy IRQ or a whole block of MSI
+    /// vectors, e.g. when its device is hot-unplugged.
+    pub fn free(&mut self, res: &Resource) -> Result<()> {
+        let freed = match *res {
+            Resource::LegacyIrq(irq) => self.legacy.free(irq, 1).is_ok(),
+            Resource::MsiIrq { ty, base, size } => match self.msi_blocks.get(&base) {
+                Some(&block) if block == (ty, size) => {
+                    self.msi_blocks.remove(&base);
+                    self.msi.free(base, size).is_ok()
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if freed {
+            Ok(())
+        } else {
+            Err(Error::NotAllocated(res.clone()))
+        }
+    }
+
+    /// Return the type and size of the MSI block starting at `base`, if
+    /// allocated.
+    pub fn msi_block(&self, base: u32) -> Option<(MsiIrqType, u32)> {
+        self.msi_blocks.get(&base).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy() {
+        let mut allocator = IrqAllocator::new((5, 7), (24, 87));
+        assert!(allocator.reserve_legacy(6).is_ok());
+        assert_eq!(
+            allocator.reserve_legacy(6),
+            Err(Error::LegacyUnavailable(6))
+        );
+        assert_eq!(
+            allocator.reserve_legacy(4),
+            Err(Error::LegacyUnavailable(4))
+        );
+        assert_eq!(allocator.allocate_legacy(), Ok(5));
+        assert_eq!(allocator.allocate_legacy(), Ok(7));
+        assert_eq!(allocator.allocate_legacy(), Err(Error::LegacyExhausted));
+
+        assert!(allocator.free(&Resource::LegacyIrq(6)).is_ok());
+        assert_eq!(
+            allocator.free(&Resource::LegacyIrq(6)),
+            Err(Error::NotAllocated(Resource::LegacyIrq(6)))
+        );
+        assert_eq!(allocator.allocate_legacy(), Ok(6));
+    }
+
+    #[test]
+    fn test_msi() {
+        let mut allocator = IrqAllocator::new((5, 15), (24, 63));
+        assert_eq!(allocator.allocate_msi(MsiIrqType::PciMsix, 3), Ok(24));
+        // PCI MSI blocks are aligned on their size.
+        assert_eq!(allocator.allocate_msi(MsiIrqType::PciMsi, 8), Ok(32));
+        assert_eq!(allocator.allocate_msi(MsiIrqType::GenericMsi, 5), Ok(27));
+        assert_eq!(allocator.msi_block(32), Some((MsiIrqType::PciMsi, 8)));
+        assert_eq!(allocator.msi_block(33), None);
+
+        for (ty, count) in [
+            (MsiIrqType::PciMsi, 0),
+            (MsiIrqType::PciMsi, 6),
+            (MsiIrqType::PciMsi, 64),
+            (MsiIrqType::PciMsix, 0),
+            (MsiIrqType::PciMsix, 4096),
+            (MsiIrqType::GenericMsi, 0),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                allocator.allocate_msi(*ty, *count),
+                Err(Error::InvalidCount {
+                    ty: *ty,
+                    count: *count
+                })
+            );
+        }
+        assert_eq!(
+            allocator.allocate_msi(MsiIrqType::PciMsi, 32),
+            Err(Error::MsiExhausted {
+                ty: MsiIrqType::PciMsi,
+                count: 32
+            })
+        );
+
+        // Blocks are freed whole and with their type.
+        let block = Resource::MsiIrq {
+            ty: MsiIrqType::PciMsi,
+            base: 32,
+            size: 8,
+        };
+        for res in [
+            Resource::MsiIrq {
+                ty: MsiIrqType::PciMsix,
+                base: 32,
+                size: 8,
+            },
+            Resource::MsiIrq {
+                ty: MsiIrqType::PciMsi,
+                base: 32,
+                size: 4,
+            },
+            Resource::PioAddressRange { base: 32, size: 8 },
+        ]
+        .iter()
+        {
+            assert_eq!(allocator.free(res), Err(Error::NotAllocated(res.clone())));
+        }
+        assert!(allocator.free(&block).is_ok());
+        assert_eq!(allocator.free(&block), Err(Error::NotAllocated(block)));
+        assert_eq!(allocator.msi_block(32), None);
+        assert_eq!(allocator.allocate_msi(MsiIrqType::PciMsi, 4), Ok(32));
+    }
+}
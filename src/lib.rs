@@ -3,10 +3,29 @@
 
 //! rust-vmm device model.
 
+extern crate arc_swap;
+#[cfg(feature = "log-tracer")]
+#[macro_use]
+extern crate log;
+#[cfg(test)]
+extern crate proptest;
+
 use std::cmp::{Ord, Ordering, PartialOrd};
+use std::fmt;
+use std::result;
 
+pub mod allocator;
+pub mod coalesced;
 pub mod device_manager;
+pub mod interval;
+pub mod ioevent;
+pub mod irq;
+pub mod memslot;
+pub mod region;
+pub mod replay;
 pub mod resources;
+pub mod stats;
+pub mod trace;
 
 // IO Size.
 #[derive(Debug, Copy, Clone)]
@@ -46,6 +65,22 @@ impl IoAddress {
             IoAddress::Mmio(m) => m,
         }
     }
+
+    // Check whether this is a port I/O address.
+    fn is_pio(&self) -> bool {
+        match *self {
+            IoAddress::Pio(_) => true,
+            IoAddress::Mmio(_) => false,
+        }
+    }
+
+    // Build an IO Address of the same type from a raw value.
+    fn with_raw_value(&self, value: u64) -> IoAddress {
+        match *self {
+            IoAddress::Pio(_) => IoAddress::Pio(value as u16),
+            IoAddress::Mmio(_) => IoAddress::Mmio(value),
+        }
+    }
 }
 
 impl Eq for IoAddress {}
@@ -64,7 +99,58 @@ impl Ord for IoAddress {
 
 impl PartialOrd for IoAddress {
     fn partial_cmp(&self, other: &IoAddress) -> Option<Ordering> {
-        self.raw_value().partial_cmp(&other.raw_value())
+        Some(self.cmp(other))
+    }
+}
+
+/// Direction of an IO access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoDirection {
+    /// The guest reads from the device.
+    Read,
+    /// The guest writes to the device.
+    Write,
+}
+
+/// Errors reported by a device when handling an IO access.
+#[derive(Debug)]
+pub enum DeviceIoError {
+    /// The device does not support accesses of this width, in bytes.
+    UnsupportedAccessWidth(usize),
+    /// Nothing answers the access at this offset within the device range.
+    BusError,
+    /// The device failed internally while handling the access.
+    Internal(String),
+}
+
+/// Simplify the `Result` type returned by `DeviceIo` accesses.
+pub type DeviceIoResult<T> = result::Result<T, DeviceIoError>;
+
+/// Identity of a device, reported in `IoManager` errors and introspection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceId {
+    /// Name of the device, e.g. "serial0".
+    pub name: String,
+    /// Type of the device, e.g. "uart".
+    pub kind: String,
+    /// Instance number among the devices of the same type.
+    pub instance: u32,
+}
+
+impl DeviceId {
+    /// Create a device identity.
+    pub fn new(name: &str, kind: &str, instance: u32) -> Self {
+        DeviceId {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            instance,
+        }
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} #{})", self.name, self.kind, self.instance)
     }
 }
 
@@ -74,12 +160,19 @@ impl PartialOrd for IoAddress {
 /// The VMM will then dispatch IO (PIO or MMIO) VM exits by calling into the
 /// registered devices read or write method from this trait.
 /// The DeviceIo trait adopts the interior mutability pattern
-/// so we can get a real multiple threads handling.
-pub trait DeviceIo: Send {
+/// so we can get a real multiple threads handling: the same device may be
+/// accessed concurrently from several vCPU threads, hence the `Send + Sync`
+/// requirement.
+pub trait DeviceIo: Send + Sync {
     /// Read from the guest physical address `base`, starting at `offset`.
     /// Result is placed in `data`.
-    fn read(&self, base: IoAddress, offset: IoAddress, data: &mut [u8]);
+    fn read(&self, base: IoAddress, offset: IoAddress, data: &mut [u8]) -> DeviceIoResult<()>;
 
     /// Write `data` to the guest physical address `base`, starting from `offset`.
-    fn write(&self, base: IoAddress, offset: IoAddress, data: &[u8]);
+    fn write(&self, base: IoAddress, offset: IoAddress, data: &[u8]) -> DeviceIoResult<()>;
+
+    /// Identity of the device, if it has one.
+    fn id(&self) -> Option<&DeviceId> {
+        None
+    }
 }
@@ -0,0 +1,178 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Allocation of KVM memory slot indexes.
+//!
+//! [MemSlotAllocator](struct.MemSlotAllocator.html) hands out the indexes of
+//! the memory slots of a VM, whose number is reported by KVM through
+//! `KVM_CAP_NR_MEMSLOTS`, and takes them back when devices are removed.
+
+use std::result;
+
+use crate::interval::IntervalAllocator;
+
+/// Error type for `MemSlotAllocator` usage.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The number of requested slots is zero.
+    InvalidCount,
+    /// The slot is already allocated or beyond the maximum.
+    Unavailable(u32),
+    /// Not enough slots are free.
+    Exhausted {
+        /// Number of requested slots.
+        count: u32,
+    },
+    /// The freed slot isn't allocated.
+    NotAllocated(u32),
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Allocator of KVM memory slot indexes.
+#[derive(Clone, Debug)]
+pub struct MemSlotAllocator {
+    max: u32,
+    slots: IntervalAllocator<u32>,
+}
+
+impl MemSlotAllocator {
+    /// Create an allocator of the slots 0 to `max` - 1, all free.
+    pub fn new(max: u32) -> Self {
+        // An empty interval when there is no slot at all.
+        let (first, last) = match max.checked_sub(1) {
+            Some(last) => (0, last),
+            None => (1, 0),
+        };
+        MemSlotAllocator {
+            max,
+            slots: IntervalAllocator::new(first, last),
+        }
+    }
+
+    /// Return the number of slots of the VM.
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    /// Allocate the `count` slots starting at `slot`, e.g. the ones used by
+    /// the boot RAM.
+    pub fn reserve(&mut self, slot: u32, count: u32) -> Result<()> {
+        if count == 0 {
+            return Err(Error::InvalidCount);
+        }
+        if let Some(taken) = (slot..slot.saturating_add(count)).find(|&s| !self.is_free(s)) {
+            return Err(Error::Unavailable(taken));
+        }
+        self.slots
+            .reserve(slot, count)
+            .map_err(|_| Error::Unavailable(slot))
+    }
+
+    /// Allocate `count` contiguous slots, preferably from `from` upwards.
+    ///
+    /// Return the first allocated slot.
+    pub fn allocate_contiguous(&mut self, count: u32, from: u32) -> Result<u32> {
+        if count == 0 {
+            return Err(Error::InvalidCount);
+        }
+        self.slots
+            .allocate(count, 1, Some((from, u32::MAX)))
+            .or_else(|_| self.slots.allocate(count, 1, None))
+            .map_err(|_| Error::Exhausted { count })
+    }
+
+    /// Allocate `count` slots, not necessarily contiguous, preferably from
+    /// `from` upwards.
+    ///
+    /// Either all the slots are allocated, or none is.
+    ///
+    /// Return the allocated slots, in allocation order.
+    pub fn allocate_scattered(&mut self, count: u32, from: u32) -> Result<Vec<u32>> {
+        if count == 0 {
+            return Err(Error::InvalidCount);
+        }
+        if count > self.max {
+            return Err(Error::Exhausted { count });
+        }
+        let mut slots = Vec::with_capacity(count as usize);
+        while slots.len() < count as usize {
+            match self
+                .slots
+                .allocate(1, 1, Some((from, u32::MAX)))
+                .or_else(|_| self.slots.allocate(1, 1, None))
+            {
+                Ok(slot) => slots.push(slot),
+                Err(_) => {
+                    for slot in slots {
+                        // Just allocated, can't fail.
+                        let _ = self.slots.free(slot, 1);
+                    }
+                    return Err(Error::Exhausted { count });
+                }
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Free `slot`, e.g. once the device or memory region using it is
+    /// removed.
+    pub fn free(&mut self, slot: u32) -> Result<()> {
+        self.slots
+            .free(slot, 1)
+            .map_err(|_| Error::NotAllocated(slot))
+    }
+
+    /// Check whether `slot` exists and is free.
+    pub fn is_free(&self, slot: u32) -> bool {
+        self.slots.is_free(slot, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate() {
+        let mut allocator = MemSlotAllocator::new(8);
+        assert_eq!(allocator.max(), 8);
+        // Boot RAM below and above the 32 bit gap.
+        assert!(allocator.reserve(0, 2).is_ok());
+        assert_eq!(allocator.reserve(1, 2), Err(Error::Unavailable(1)));
+        assert_eq!(allocator.reserve(7, 2), Err(Error::Unavailable(8)));
+        assert_eq!(allocator.reserve(2, 0), Err(Error::InvalidCount));
+
+        assert_eq!(allocator.allocate_contiguous(2, 5), Ok(5));
+        // Wraps around once no slot above the preferred one fits.
+        assert_eq!(allocator.allocate_contiguous(2, 6), Ok(2));
+        assert_eq!(
+            allocator.allocate_scattered(3, 7),
+            Err(Error::Exhausted { count: 3 })
+        );
+        assert!(allocator.is_free(4));
+        assert!(allocator.is_free(7));
+        assert_eq!(allocator.allocate_scattered(2, 7), Ok(vec![7, 4]));
+        assert_eq!(
+            allocator.allocate_contiguous(1, 0),
+            Err(Error::Exhausted { count: 1 })
+        );
+
+        assert!(allocator.free(6).is_ok());
+        assert_eq!(allocator.free(6), Err(Error::NotAllocated(6)));
+        assert_eq!(allocator.free(8), Err(Error::NotAllocated(8)));
+        assert_eq!(allocator.allocate_scattered(1, 0), Ok(vec![6]));
+        assert_eq!(
+            allocator.allocate_scattered(u32::MAX, 0),
+            Err(Error::Exhausted { count: u32::MAX })
+        );
+
+        let mut allocator = MemSlotAllocator::new(0);
+        assert_eq!(
+            allocator.allocate_scattered(1, 0),
+            Err(Error::Exhausted { count: 1 })
+        );
+        assert_eq!(allocator.reserve(0, 1), Err(Error::Unavailable(0)));
+    }
+}
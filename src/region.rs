@@ -0,0 +1,332 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Hierarchical description of IO address spaces.
+//!
+//! An [IoRegion](struct.IoRegion.html) is either a device region, handled by
+//! a `DeviceIo` object, or a container holding subregions at offsets relative
+//! to the container base, e.g. a PCI host bridge window holding BARs. Where
+//! subregions overlap, the one with the highest priority is visible, and
+//! unpopulated parts of a container let lower priority regions show through.
+//!
+//! The tree is flattened into plain IO ranges when registered with
+//! [IoManager::register_region](../device_manager/struct.IoManager.html#method.register_region),
+//! so dispatching VM exits is as fast as for devices registered directly.
+//! The tree is flattened again when one of its devices is unregistered.
+
+use std::cmp::Reverse;
+use std::result;
+use std::sync::Arc;
+
+use crate::DeviceIo;
+
+/// Error type for `IoRegion` usage.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Subregions can only be added to container regions.
+    NotContainer,
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Clone)]
+enum RegionKind {
+    Container(Vec<Subregion>),
+    Device(Arc<dyn DeviceIo>),
+}
+
+#[derive(Clone)]
+struct Subregion {
+    offset: u64,
+    priority: i32,
+    region: IoRegion,
+}
+
+/// Node of an IO region tree.
+#[derive(Clone)]
+pub struct IoRegion {
+    size: u64,
+    kind: RegionKind,
+}
+
+impl IoRegion {
+    /// Create a container region of `size` bytes, with no subregion.
+    pub fn new_container(size: u64) -> Self {
+        IoRegion {
+            size,
+            kind: RegionKind::Container(Vec::new()),
+        }
+    }
+
+    /// Create a region of `size` bytes handled by `device`.
+    pub fn new_device(size: u64, device: Arc<dyn DeviceIo>) -> Self {
+        IoRegion {
+            size,
+            kind: RegionKind::Device(device),
+        }
+    }
+
+    /// Size of the region.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Add a subregion to a container region.
+    ///
+    /// Among overlapping subregions, the one with the highest `priority` is
+    /// visible. With equal priorities, the last added subregion is visible.
+    /// Parts of the subregion outside of the container are not visible.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: offset of the subregion in the container.
+    /// * `priority`: priority of the subregion among its siblings.
+    /// * `region`: the subregion.
+    pub fn add_subregion(&mut self, offset: u64, priority: i32, region: IoRegion) -> Result<()> {
+        let subregions = match self.kind {
+            RegionKind::Container(ref mut subregions) => subregions,
+            RegionKind::Device(_) => return Err(Error::NotContainer),
+        };
+        subregions.push(Subregion {
+            offset,
+            priority,
+            region,
+        });
+        Ok(())
+    }
+
+    /// Remove the last added subregion at `offset` with `priority`.
+    pub fn remove_subregion(&mut self, offset: u64, priority: i32) -> Option<IoRegion> {
+        let subregions = match self.kind {
+            RegionKind::Container(ref mut subregions) => subregions,
+            RegionKind::Device(_) => return None,
+        };
+        let idx = subregions
+            .iter()
+            .rposition(|sub| sub.offset == offset && sub.priority == priority)?;
+        Some(subregions.remove(idx).region)
+    }
+
+    // Flatten the tree, with the region starting at offset 0.
+    pub(crate) fn flatten(&self) -> FlatView {
+        self.flatten_with(&|_| true)
+    }
+
+    // Flatten the tree, the device regions whose index in
+    // `FlatView::leaves` fails `visible` being listed but not shown.
+    pub(crate) fn flatten_with(&self, visible: &dyn Fn(usize) -> bool) -> FlatView {
+        let mut flat = FlatView::default();
+        self.render(0, (0, u128::from(self.size)), visible, &mut flat);
+        flat.ranges.sort_by_key(|range| range.start);
+        flat
+    }
+
+    // Render the region at `base` into `flat`, restricted to the `clip`
+    // window. Regions rendered first have precedence.
+    fn render(
+        &self,
+        base: u128,
+        clip: (u128, u128),
+        visible: &dyn Fn(usize) -> bool,
+        flat: &mut FlatView,
+    ) {
+        let start = base.max(clip.0);
+        let end = (base + u128::from(self.size)).min(clip.1);
+        match self.kind {
+            RegionKind::Device(ref device) => {
+                flat.leaves.push(FlatLeaf {
+                    device: device.clone(),
+                    base,
+                    size: self.size,
+                });
+                let leaf = flat.leaves.len() - 1;
+                if start < end && visible(leaf) {
+                    flat.fill(start, end, leaf);
+                }
+            }
+            RegionKind::Container(ref subregions) => {
+                // Highest priority first, the last added first among equals.
+                let mut ordered: Vec<&Subregion> = subregions.iter().rev().collect();
+                ordered.sort_by_key(|sub| Reverse(sub.priority));
+                for sub in ordered {
+                    sub.region
+                        .render(base + u128::from(sub.offset), (start, end), visible, flat);
+                }
+            }
+        }
+    }
+}
+
+// Device region of a flattened tree.
+pub(crate) struct FlatLeaf {
+    pub device: Arc<dyn DeviceIo>,
+    // Offset of the region from the root region, which nested offsets may
+    // push past the 64 bit address space.
+    pub base: u128,
+    pub size: u64,
+}
+
+// Visible part of a device region in a flattened tree.
+pub(crate) struct FlatRange {
+    // Offset of the range from the root region.
+    pub start: u64,
+    pub size: u64,
+    // Index of the device region in `FlatView::leaves`.
+    pub leaf: usize,
+}
+
+// Flattened IO region tree.
+#[derive(Default)]
+pub(crate) struct FlatView {
+    // All the device regions, including fully hidden ones.
+    pub leaves: Vec<FlatLeaf>,
+    // Non overlapping visible ranges, sorted by offset.
+    pub ranges: Vec<FlatRange>,
+}
+
+impl FlatView {
+    // Map the parts of [start, end) no range covers yet to `leaf`.
+    fn fill(&mut self, start: u128, end: u128, leaf: usize) {
+        let mut covered: Vec<(u128, u128)> = self
+            .ranges
+            .iter()
+            .map(|r| {
+                (
+                    u128::from(r.start),
+                    u128::from(r.start) + u128::from(r.size),
+                )
+            })
+            .filter(|&(s, e)| s < end && e > start)
+            .collect();
+        covered.sort();
+
+        let mut cur = start;
+        let mut gaps = Vec::new();
+        for (s, e) in covered {
+            if s > cur {
+                gaps.push((cur, s));
+            }
+            cur = cur.max(e);
+        }
+        if cur < end {
+            gaps.push((cur, end));
+        }
+        for (s, e) in gaps {
+            self.ranges.push(FlatRange {
+                start: s as u64,
+                size: (e - s) as u64,
+                leaf,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceIoResult, IoAddress};
+
+    struct NullDevice;
+
+    impl DeviceIo for NullDevice {
+        fn read(
+            &self,
+            _base: IoAddress,
+            _offset: IoAddress,
+            _data: &mut [u8],
+        ) -> DeviceIoResult<()> {
+            Ok(())
+        }
+
+        fn write(&self, _base: IoAddress, _offset: IoAddress, _data: &[u8]) -> DeviceIoResult<()> {
+            Ok(())
+        }
+    }
+
+    fn device(size: u64) -> IoRegion {
+        IoRegion::new_device(size, Arc::new(NullDevice))
+    }
+
+    fn ranges(flat: &FlatView) -> Vec<(u64, u64, usize)> {
+        flat.ranges
+            .iter()
+            .map(|r| (r.start, r.size, r.leaf))
+            .collect()
+    }
+
+    #[test]
+    fn test_add_subregion() {
+        let mut root = IoRegion::new_container(0x1000);
+        assert!(root.add_subregion(0x800, 0, device(0x800)).is_ok());
+
+        let mut leaf = device(0x10);
+        assert_eq!(
+            leaf.add_subregion(0, 0, device(0x10)),
+            Err(Error::NotContainer)
+        );
+
+        assert!(root.remove_subregion(0x800, 1).is_none());
+        assert_eq!(root.remove_subregion(0x800, 0).unwrap().size(), 0x800);
+        assert!(root.flatten().ranges.is_empty());
+    }
+
+    #[test]
+    fn test_flatten_priorities() {
+        // A window holding two BARs, partly hidden by an overlay.
+        let mut window = IoRegion::new_container(0x1000);
+        window.add_subregion(0x0, 0, device(0x400)).unwrap();
+        window.add_subregion(0x800, 0, device(0x400)).unwrap();
+
+        let mut root = IoRegion::new_container(0x2000);
+        root.add_subregion(0x0, 0, window).unwrap();
+        root.add_subregion(0x200, 1, device(0x800)).unwrap();
+        // Shows through the holes of the window only.
+        root.add_subregion(0x0, -1, device(0x2000)).unwrap();
+
+        let flat = root.flatten();
+        assert_eq!(flat.leaves.len(), 4);
+        // Overlay first, then the BARs, then the background.
+        assert_eq!(flat.leaves[0].base, 0x200);
+        assert_eq!(flat.leaves[1].base, 0x800);
+        assert_eq!(flat.leaves[2].base, 0x0);
+        assert_eq!(flat.leaves[3].base, 0x0);
+        assert_eq!(
+            ranges(&flat),
+            vec![
+                (0x0, 0x200, 2),
+                (0x200, 0x800, 0),
+                (0xa00, 0x200, 1),
+                (0xc00, 0x1400, 3),
+            ]
+        );
+
+        // Hidden device regions let the ones below show through.
+        let flat = root.flatten_with(&|leaf| leaf != 0);
+        assert_eq!(flat.leaves.len(), 4);
+        assert_eq!(
+            ranges(&flat),
+            vec![
+                (0x0, 0x400, 2),
+                (0x400, 0x400, 3),
+                (0x800, 0x400, 1),
+                (0xc00, 0x1400, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flatten_clipping() {
+        // Subregions are clipped to their container, and the last added one
+        // wins among equal priorities.
+        let mut inner = IoRegion::new_container(0x100);
+        inner.add_subregion(0x80, 0, device(0x80)).unwrap();
+        let mut outer = IoRegion::new_container(0x100);
+        outer.add_subregion(0x40, 0, inner).unwrap();
+        outer.add_subregion(0xa0, 0, device(0x10)).unwrap();
+
+        let flat = outer.flatten();
+        assert_eq!(ranges(&flat), vec![(0xa0, 0x10, 0), (0xc0, 0x40, 1)]);
+        assert_eq!(flat.leaves[1].base, 0xc0);
+    }
+}
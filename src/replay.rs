@@ -0,0 +1,391 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Recording and replay of the IO accesses seen by an `IoManager`.
+//!
+//! An [IoRecorder](struct.IoRecorder.html) set as the tracer of an
+//! `IoManager` serializes every dispatched access, with the data returned by
+//! reads, into a compact binary trace. [replay](fn.replay.html) then drives
+//! another `IoManager` with the same accesses and checks that reads return
+//! the recorded data, turning traces captured from a guest into device tests.
+//!
+//! A trace starts with the `IOTR` magic and a version byte, followed by one
+//! record per access:
+//! * a flags byte: bit 0 for writes, bit 1 for MMIO, bit 2 for successful
+//!   accesses,
+//! * the address, 2 bytes for PIO and 8 bytes for MMIO,
+//! * the data length on 2 bytes, followed by the data.
+//!
+//! All integers are little endian.
+
+use std::result;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::device_manager::{self, IoManager};
+use crate::trace::{IoAccess, IoTracer, TraceRecord};
+use crate::{IoAddress, IoDirection};
+
+const MAGIC: &[u8] = b"IOTR";
+const VERSION: u8 = 1;
+
+const FLAG_WRITE: u8 = 1 << 0;
+const FLAG_MMIO: u8 = 1 << 1;
+const FLAG_OK: u8 = 1 << 2;
+
+/// Error type for trace decoding and replay.
+#[derive(Debug)]
+pub enum Error {
+    /// The trace is malformed at this byte offset.
+    InvalidTrace(usize),
+    /// The access succeeded during replay but failed when recorded, or the
+    /// other way around.
+    ResultMismatch {
+        /// Index of the access in the trace.
+        index: usize,
+        /// The recorded access.
+        record: TraceRecord,
+        /// The replay error, if the replayed access failed.
+        error: Option<Box<device_manager::Error>>,
+    },
+    /// The replayed read returned other data than the recorded one.
+    DataMismatch {
+        /// Index of the access in the trace.
+        index: usize,
+        /// The recorded access.
+        record: TraceRecord,
+        /// The data returned by the replayed read.
+        data: Vec<u8>,
+    },
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Tracer recording the accesses dispatched by an `IoManager` into a trace.
+pub struct IoRecorder {
+    trace: Mutex<Vec<u8>>,
+}
+
+impl Default for IoRecorder {
+    fn default() -> Self {
+        IoRecorder::new()
+    }
+}
+
+impl IoRecorder {
+    /// Create a recorder with an empty trace.
+    pub fn new() -> Self {
+        let mut trace = MAGIC.to_vec();
+        trace.push(VERSION);
+        IoRecorder {
+            trace: Mutex::new(trace),
+        }
+    }
+
+    /// Return the trace recorded so far.
+    pub fn trace(&self) -> Vec<u8> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        // Records are appended in one go, a poisoned trace is still valid.
+        self.trace
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl IoTracer for IoRecorder {
+    fn after(&self, access: &IoAccess, result: &device_manager::Result<()>) {
+        let mut flags = 0;
+        if access.dir == IoDirection::Write {
+            flags |= FLAG_WRITE;
+        }
+        if result.is_ok() {
+            flags |= FLAG_OK;
+        }
+        let mut record = Vec::with_capacity(access.data.len() + 11);
+        match access.addr {
+            IoAddress::Pio(addr) => {
+                record.push(flags);
+                record.extend_from_slice(&addr.to_le_bytes());
+            }
+            IoAddress::Mmio(addr) => {
+                record.push(flags | FLAG_MMIO);
+                record.extend_from_slice(&addr.to_le_bytes());
+            }
+        }
+        // Longer accesses don't exist on real buses.
+        let len = access.data.len().min(usize::from(u16::MAX));
+        record.extend_from_slice(&(len as u16).to_le_bytes());
+        record.extend_from_slice(&access.data[..len]);
+        self.lock().extend_from_slice(&record);
+    }
+}
+
+/// Decode the accesses of a trace, in order.
+pub fn decode(trace: &[u8]) -> Result<Vec<TraceRecord>> {
+    if trace.len() <= MAGIC.len() || &trace[..MAGIC.len()] != MAGIC {
+        return Err(Error::InvalidTrace(0));
+    }
+    if trace[MAGIC.len()] != VERSION {
+        return Err(Error::InvalidTrace(MAGIC.len()));
+    }
+
+    let mut records = Vec::new();
+    let mut pos = MAGIC.len() + 1;
+    while pos < trace.len() {
+        let start = pos;
+        let take = |pos: &mut usize, len: usize| -> Result<&[u8]> {
+            let bytes = trace
+                .get(*pos..*pos + len)
+                .ok_or(Error::InvalidTrace(start))?;
+            *pos += len;
+            Ok(bytes)
+        };
+        let flags = take(&mut pos, 1)?[0];
+        if flags & !(FLAG_WRITE | FLAG_MMIO | FLAG_OK) != 0 {
+            return Err(Error::InvalidTrace(start));
+        }
+        let addr = if flags & FLAG_MMIO != 0 {
+            let mut addr = [0; 8];
+            addr.copy_from_slice(take(&mut pos, 8)?);
+            IoAddress::Mmio(u64::from_le_bytes(addr))
+        } else {
+            let mut addr = [0; 2];
+            addr.copy_from_slice(take(&mut pos, 2)?);
+            IoAddress::Pio(u16::from_le_bytes(addr))
+        };
+        let mut len = [0; 2];
+        len.copy_from_slice(take(&mut pos, 2)?);
+        let data = take(&mut pos, usize::from(u16::from_le_bytes(len)))?.to_vec();
+        records.push(TraceRecord {
+            dir: if flags & FLAG_WRITE != 0 {
+                IoDirection::Write
+            } else {
+                IoDirection::Read
+            },
+            addr,
+            data,
+            handle: None,
+            ok: flags & FLAG_OK != 0,
+        });
+    }
+    Ok(records)
+}
+
+/// Replay the accesses of a trace on `io_mgr`, in order.
+///
+/// Every access must succeed or fail as it did when recorded, and successful
+/// reads must return the recorded data. Replay stops at the first mismatch.
+///
+/// Return the number of replayed accesses.
+pub fn replay(io_mgr: &IoManager, trace: &[u8]) -> Result<usize> {
+    let records = decode(trace)?;
+    for (index, record) in records.iter().enumerate() {
+        // Reads start from data differing from the recorded one in every
+        // byte, so that a device not filling the buffer is caught.
+        let mut data = match record.dir {
+            IoDirection::Read => record.data.iter().map(|b| !b).collect(),
+            IoDirection::Write => record.data.clone(),
+        };
+        let result = match (record.dir, record.addr) {
+            (IoDirection::Read, IoAddress::Pio(addr)) => io_mgr.pio_read(addr, &mut data),
+            (IoDirection::Read, IoAddress::Mmio(addr)) => io_mgr.mmio_read(addr, &mut data),
+            (IoDirection::Write, IoAddress::Pio(addr)) => io_mgr.pio_write(addr, &data),
+            (IoDirection::Write, IoAddress::Mmio(addr)) => io_mgr.mmio_write(addr, &data),
+        };
+        if result.is_ok() != record.ok {
+            return Err(Error::ResultMismatch {
+                index,
+                record: record.clone(),
+                error: result.err().map(Box::new),
+            });
+        }
+        if record.ok && data != record.data {
+            return Err(Error::DataMismatch {
+                index,
+                record: record.clone(),
+                data,
+            });
+        }
+    }
+    Ok(records.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::resources::Resource;
+    use crate::{DeviceIo, DeviceIoResult};
+
+    // Device with a single byte register, reads returning the last written
+    // value plus `bias`.
+    struct RegDevice {
+        value: Mutex<u8>,
+        bias: u8,
+    }
+
+    impl RegDevice {
+        fn new(bias: u8) -> Arc<Self> {
+            Arc::new(RegDevice {
+                value: Mutex::new(0),
+                bias,
+            })
+        }
+    }
+
+    impl DeviceIo for RegDevice {
+        fn read(
+            &self,
+            _base: IoAddress,
+            _offset: IoAddress,
+            data: &mut [u8],
+        ) -> DeviceIoResult<()> {
+            let value = *self.value.lock().unwrap();
+            for byte in data.iter_mut() {
+                *byte = value.wrapping_add(self.bias);
+            }
+            Ok(())
+        }
+
+        fn write(&self, _base: IoAddress, _offset: IoAddress, data: &[u8]) -> DeviceIoResult<()> {
+            *self.value.lock().unwrap() = data[0];
+            Ok(())
+        }
+    }
+
+    // Device whose reads leave the buffer untouched.
+    struct NullDevice;
+
+    impl DeviceIo for NullDevice {
+        fn read(
+            &self,
+            _base: IoAddress,
+            _offset: IoAddress,
+            _data: &mut [u8],
+        ) -> DeviceIoResult<()> {
+            Ok(())
+        }
+
+        fn write(&self, _base: IoAddress, _offset: IoAddress, _data: &[u8]) -> DeviceIoResult<()> {
+            Ok(())
+        }
+    }
+
+    fn io_manager(device: Arc<dyn DeviceIo>) -> IoManager {
+        let mut io_mgr = IoManager::new();
+        let res = [
+            Resource::PioAddressRange {
+                base: 0x3f8,
+                size: 8,
+            },
+            Resource::MmioAddressRange {
+                base: 0xd000_0000,
+                size: 0x1000,
+            },
+        ];
+        io_mgr.register_device_io(device, &res).unwrap();
+        io_mgr
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let mut io_mgr = io_manager(RegDevice::new(0));
+        let recorder = Arc::new(IoRecorder::new());
+        io_mgr.set_tracer(Some(recorder.clone()));
+        let mut data = [0; 4];
+        io_mgr.pio_write(0x3f8, &[0x41]).unwrap();
+        io_mgr.pio_read(0x3f9, &mut data[..1]).unwrap();
+        io_mgr.mmio_write(0xd000_0010, &[7, 0, 0, 0]).unwrap();
+        io_mgr.mmio_read(0xd000_0010, &mut data).unwrap();
+        assert!(io_mgr.mmio_read(0xe000_0000, &mut data).is_err());
+        let trace = recorder.trace();
+
+        assert_eq!(replay(&io_manager(RegDevice::new(0)), &trace).unwrap(), 5);
+
+        match replay(&io_manager(RegDevice::new(1)), &trace) {
+            Err(Error::DataMismatch {
+                index,
+                record,
+                data,
+            }) => {
+                assert_eq!(index, 1);
+                assert_eq!(record.data, vec![0x41]);
+                assert_eq!(data, vec![0x42]);
+            }
+            _ => panic!("replay with mismatching reads succeeded"),
+        }
+        match replay(&io_manager(Arc::new(NullDevice)), &trace) {
+            Err(Error::DataMismatch { index, data, .. }) => {
+                assert_eq!(index, 1);
+                assert_eq!(data, vec![!0x41]);
+            }
+            _ => panic!("replay with reads not filling the buffer succeeded"),
+        }
+
+        let mut io_mgr = io_manager(RegDevice::new(0));
+        io_mgr.set_mmio_fallback(Some(Arc::new(device_manager::OpenBus::new())));
+        match replay(&io_mgr, &trace) {
+            Err(Error::ResultMismatch { index, error, .. }) => {
+                assert_eq!(index, 4);
+                assert!(error.is_none());
+            }
+            _ => panic!("replay with mismatching results succeeded"),
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let recorder = IoRecorder::new();
+        let accesses = [
+            (IoDirection::Write, IoAddress::Pio(0x3f8), vec![0x41], true),
+            (
+                IoDirection::Read,
+                IoAddress::Mmio(0xd000_0000),
+                vec![1, 2, 3, 4],
+                false,
+            ),
+        ];
+        for (dir, addr, data, ok) in accesses.iter() {
+            let access = IoAccess {
+                dir: *dir,
+                addr: *addr,
+                data,
+                handle: None,
+                device: None,
+            };
+            let result = if *ok {
+                Ok(())
+            } else {
+                Err(device_manager::Error::NoDevice)
+            };
+            recorder.after(&access, &result);
+        }
+
+        let trace = recorder.trace();
+        assert_eq!(trace.len(), 5 + 6 + 15);
+        let records = decode(&trace).unwrap();
+        assert_eq!(records.len(), 2);
+        for (record, (dir, addr, data, ok)) in records.iter().zip(accesses.iter()) {
+            assert_eq!(record.dir, *dir);
+            assert_eq!(record.addr, *addr);
+            assert_eq!(record.addr.is_pio(), addr.is_pio());
+            assert_eq!(record.data, *data);
+            assert_eq!(record.ok, *ok);
+        }
+
+        assert_eq!(decode(&trace[..5]).unwrap().len(), 0);
+        assert!(decode(&[]).is_err());
+        match decode(&trace[..trace.len() - 1]) {
+            Err(Error::InvalidTrace(offset)) => assert_eq!(offset, 11),
+            _ => panic!("truncated trace was decoded"),
+        }
+        let mut bad = trace.clone();
+        bad[4] = VERSION + 1;
+        assert!(decode(&bad).is_err());
+    }
+}
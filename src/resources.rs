@@ -12,8 +12,6 @@
 //! 5) the VMM registers the new device onto corresponding device managers according the allocated
 //!    resources.
 
-use std::{u16, u32, u64};
-
 /// Enumeration describing a device's resource constraints.
 pub enum ResourceConstraint {
     /// Constraint for an IO Port address range.
@@ -108,7 +106,7 @@ impl ResourceConstraint {
 }
 
 /// Type of Message Singaled Interrupt
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum MsiIrqType {
     /// PCI MSI IRQ numbers.
     PciMsi,
@@ -120,7 +118,7 @@ pub enum MsiIrqType {
 
 /// Enumeration for device resources.
 #[allow(missing_docs)]
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Resource {
     /// IO Port address range.
     PioAddressRange { base: u16, size: u16 },
@@ -245,6 +243,12 @@ impl DeviceResources {
     }
 }
 
+impl From<Vec<Resource>> for DeviceResources {
+    fn from(resources: Vec<Resource>) -> Self {
+        DeviceResources(resources)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -370,6 +374,8 @@ mod tests {
     fn test_get_all_resources() {
         let resources = get_device_resource();
         assert_eq!(resources.get_all_resources().len(), 8);
+        let copy = DeviceResources::from(resources.get_all_resources().to_vec());
+        assert_eq!(copy.get_all_resources(), resources.get_all_resources());
     }
 
     #[test]
@@ -0,0 +1,136 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Access statistics of IO ranges.
+//!
+//! Once enabled with `IoManager::set_stats_enabled`, every access dispatched
+//! by an `IoManager` is counted on the range handling it, or on the bus when
+//! no device claims it. Counters are atomics sharded across threads, cheap
+//! enough to be left on in production.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::IoDirection;
+
+// Number of counter shards, vCPU threads being spread over them.
+const SHARDS: usize = 8;
+
+// Access widths counted separately, any other width is counted as such.
+const WIDTHS: [usize; 4] = [1, 2, 4, 8];
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+}
+
+/// Snapshot of the access counters of an IO range.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct IoStats {
+    /// Number of reads.
+    pub reads: u64,
+    /// Number of writes.
+    pub writes: u64,
+    /// Number of bytes read.
+    pub bytes_read: u64,
+    /// Number of bytes written.
+    pub bytes_written: u64,
+    /// Number of accesses of 1, 2, 4 and 8 bytes.
+    pub widths: [u64; 4],
+    /// Number of accesses of any other width.
+    pub other_widths: u64,
+}
+
+impl IoStats {
+    /// Total number of accesses.
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+// Counters updated by a subset of the threads, on their own cache line.
+#[repr(align(64))]
+#[derive(Default)]
+struct Shard {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    widths: [AtomicU64; 4],
+    other_widths: AtomicU64,
+}
+
+// Access counters of an IO range, shared by all the copies of an `IoManager`.
+#[derive(Default)]
+pub(crate) struct IoCounters {
+    shards: [Shard; SHARDS],
+}
+
+impl IoCounters {
+    // Count an access of `len` bytes.
+    pub(crate) fn record(&self, dir: IoDirection, len: usize) {
+        let shard = &self.shards[SHARD.with(|shard| *shard)];
+        let (count, bytes) = match dir {
+            IoDirection::Read => (&shard.reads, &shard.bytes_read),
+            IoDirection::Write => (&shard.writes, &shard.bytes_written),
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+        match WIDTHS.iter().position(|&width| width == len) {
+            Some(idx) => shard.widths[idx].fetch_add(1, Ordering::Relaxed),
+            None => shard.other_widths.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    // Sum up the counters of all the shards.
+    pub(crate) fn snapshot(&self) -> IoStats {
+        let mut stats = IoStats::default();
+        for shard in self.shards.iter() {
+            stats.reads += shard.reads.load(Ordering::Relaxed);
+            stats.writes += shard.writes.load(Ordering::Relaxed);
+            stats.bytes_read += shard.bytes_read.load(Ordering::Relaxed);
+            stats.bytes_written += shard.bytes_written.load(Ordering::Relaxed);
+            for (total, count) in stats.widths.iter_mut().zip(shard.widths.iter()) {
+                *total += count.load(Ordering::Relaxed);
+            }
+            stats.other_widths += shard.other_widths.load(Ordering::Relaxed);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_io_counters() {
+        let counters = Arc::new(IoCounters::default());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counters = counters.clone();
+                thread::spawn(move || {
+                    for len in [1, 2, 4, 8, 3].iter() {
+                        counters.record(IoDirection::Read, *len);
+                        counters.record(IoDirection::Write, *len);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.reads, 20);
+        assert_eq!(stats.writes, 20);
+        assert_eq!(stats.accesses(), 40);
+        assert_eq!(stats.bytes_read, 72);
+        assert_eq!(stats.bytes_written, 72);
+        assert_eq!(stats.widths, [8; 4]);
+        assert_eq!(stats.other_widths, 8);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Tracing of the IO accesses dispatched by an `IoManager`.
+//!
+//! An [IoTracer](trait.IoTracer.html) set with `IoManager::set_tracer` sees
+//! every PIO and MMIO access before and after it is dispatched, along with
+//! the device it resolved to, without wrapping any device.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::device_manager::{DeviceHandle, Result};
+use crate::{DeviceIo, IoAddress, IoDirection};
+
+/// IO access dispatched by an `IoManager`, as seen by an `IoTracer`.
+pub struct IoAccess<'a> {
+    /// Direction of the access.
+    pub dir: IoDirection,
+    /// Guest address of the access.
+    pub addr: IoAddress,
+    /// Data written by the guest, or returned to the guest once a read is
+    /// dispatched.
+    pub data: &'a [u8],
+    /// Handle of the registered device the address resolved to, if any.
+    pub handle: Option<DeviceHandle>,
+    /// Device the address resolved to, registered or fallback, if any.
+    pub device: Option<&'a Arc<dyn DeviceIo>>,
+}
+
+/// Tracer called by an `IoManager` around each dispatched access.
+///
+/// Tracers are called from all the vCPU threads dispatching VM exits, and
+/// must not call back into the `IoManager`.
+pub trait IoTracer: Send + Sync {
+    /// Called before dispatching `access`. The data of reads is the content
+    /// of the guest buffer before the access.
+    fn before(&self, _access: &IoAccess) {}
+
+    /// Called once `access` is dispatched, with the dispatch result.
+    fn after(&self, _access: &IoAccess, _result: &Result<()>) {}
+}
+
+/// Dispatched IO access kept by a `RingTracer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// Direction of the access.
+    pub dir: IoDirection,
+    /// Guest address of the access.
+    pub addr: IoAddress,
+    /// Data written by the guest or returned to the guest.
+    pub data: Vec<u8>,
+    /// Handle of the registered device the address resolved to, if any.
+    pub handle: Option<DeviceHandle>,
+    /// Whether the access was successfully dispatched.
+    pub ok: bool,
+}
+
+/// Tracer keeping the last dispatched accesses in a bounded ring buffer.
+pub struct RingTracer {
+    capacity: usize,
+    records: Mutex<VecDeque<TraceRecord>>,
+}
+
+impl RingTracer {
+    /// Create a tracer keeping up to `capacity` accesses, older accesses
+    /// being dropped first.
+    pub fn new(capacity: usize) -> Self {
+        RingTracer {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Return the kept accesses, oldest first.
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Return and forget the kept accesses, oldest first.
+    pub fn take(&self) -> Vec<TraceRecord> {
+        self.lock().drain(..).collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<TraceRecord>> {
+        // Records stay consistent even if a thread panicked while tracing.
+        self.records
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl IoTracer for RingTracer {
+    fn after(&self, access: &IoAccess, result: &Result<()>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.lock();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(TraceRecord {
+            dir: access.dir,
+            addr: access.addr,
+            data: access.data.to_vec(),
+            handle: access.handle,
+            ok: result.is_ok(),
+        });
+    }
+}
+
+/// Tracer logging each dispatched access through the `log` crate.
+#[cfg(feature = "log-tracer")]
+pub struct LogTracer {
+    level: log::Level,
+}
+
+#[cfg(feature = "log-tracer")]
+impl LogTracer {
+    /// Create a tracer logging accesses at `level`.
+    pub fn new(level: log::Level) -> Self {
+        LogTracer { level }
+    }
+}
+
+#[cfg(feature = "log-tracer")]
+impl IoTracer for LogTracer {
+    fn after(&self, access: &IoAccess, result: &Result<()>) {
+        let device = access
+            .device
+            .and_then(|device| device.id())
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        match result {
+            Ok(()) => log!(
+                self.level,
+                "{:?} {:?} {:x?} {}",
+                access.dir,
+                access.addr,
+                access.data,
+                device
+            ),
+            Err(e) => log!(
+                self.level,
+                "{:?} {:?} {:x?} {} failed: {:?}",
+                access.dir,
+                access.addr,
+                access.data,
+                device,
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device_manager::Error;
+
+    #[test]
+    fn test_ring_tracer() {
+        let tracer = RingTracer::new(2);
+        for addr in [0x10, 0x20, 0x30].iter() {
+            let result = match *addr {
+                0x20 => Err(Error::NoDevice),
+                _ => Ok(()),
+            };
+            let access = IoAccess {
+                dir: IoDirection::Write,
+                addr: IoAddress::Pio(*addr),
+                data: &[1, 2],
+                handle: None,
+                device: None,
+            };
+            tracer.before(&access);
+            tracer.after(&access, &result);
+        }
+
+        let records = tracer.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].addr, IoAddress::Pio(0x20));
+        assert!(!records[0].ok);
+        assert_eq!(records[1].data, vec![1, 2]);
+        assert!(records[1].ok);
+        assert_eq!(tracer.take(), records);
+        assert!(tracer.records().is_empty());
+    }
+}